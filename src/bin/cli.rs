@@ -1,25 +1,367 @@
-use eyre::Context;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand, ValueEnum};
+use eyre::{eyre, Context};
 use ical_merger::lib::{
-    calendar::{filter_future_days, hide_details, urls_to_merged_calendar},
-    config::Config,
+    calendar::{available_events, feed_busy_slots, feed_working_periods, finish_feed, freebusy_feed, load_calendar, load_components, set_prodid, SourceFailure},
+    config::{Config, FeedConfig},
+    convert::events_to_json,
+    diff::diff_calendars,
+    fetch::Fetcher,
+    free::{free_slots, parse_duration_param, parse_time_param, slots_to_events, slots_to_json, vfreebusy},
+    logging::init_logging,
+    server::start_server,
+    stats::feed_stats,
+    window::{start_of_day, TimeWindow},
 };
+use icalendar::Calendar;
+
+/// Some sources could not be read; the output was written without them.
+const EXIT_PARTIAL: u8 = 2;
+/// `diff` found differences.
+const EXIT_DIFFERENT: u8 = 3;
+
+/// Merges iCalendar feeds. Without `--config` and source arguments, the
+/// configuration is read from the same environment variables as the server.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// TOML config file, replaces `CONFIG_FILE`.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Merges the sources of a feed, or the given urls, into one calendar.
+    Merge {
+        /// Calendar urls to merge instead of a configured feed.
+        sources: Vec<String>,
+
+        #[command(flatten)]
+        feed: FeedArgs,
+
+        #[arg(long, value_enum, default_value_t = MergeFormat::Ics)]
+        format: MergeFormat,
+
+        #[command(flatten)]
+        range: RangeArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Checks the configuration and lists the feeds it defines.
+    Validate,
+    /// Prints the meeting load of a feed as JSON.
+    Stats {
+        #[command(flatten)]
+        feed: FeedArgs,
+
+        #[command(flatten)]
+        range: RangeArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Lists the free slots of a feed within its working hours.
+    Free {
+        #[command(flatten)]
+        feed: FeedArgs,
+
+        #[command(flatten)]
+        range: RangeArgs,
+
+        /// Minimum length of a slot, e.g. `30m` or `1h30m`.
+        #[arg(long)]
+        duration: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = FreeFormat::Json)]
+        format: FreeFormat,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Reads a calendar file or url and writes it normalized or as JSON.
+    Convert {
+        input: String,
+
+        #[arg(long, value_enum, default_value_t = ConvertFormat::Ics)]
+        format: ConvertFormat,
+
+        /// Timezone for floating times and the JSON output.
+        #[arg(long, default_value = "UTC")]
+        timezone: Tz,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Lists the events added, removed or changed between two calendars.
+    Diff {
+        old: String,
+        new: String,
+    },
+    /// Starts the HTTP server.
+    Serve,
+}
+
+#[derive(clap::Args, Debug)]
+struct FeedArgs {
+    /// Name of the feed, defaults to the first one.
+    #[arg(long)]
+    feed: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RangeArgs {
+    /// Start of the range, a date or an RFC 3339 time.
+    #[arg(long)]
+    from: Option<String>,
+
+    /// End of the range, a date or an RFC 3339 time.
+    #[arg(long)]
+    to: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct OutputArgs {
+    /// Write to this file instead of stdout.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum MergeFormat {
+    Ics,
+    Freebusy,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FreeFormat {
+    Json,
+    Ics,
+    Freebusy,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ConvertFormat {
+    Ics,
+    Json,
+}
 
 #[tokio::main]
-async fn main() -> eyre::Result<()> {
+async fn main() -> ExitCode {
     dotenvy::dotenv().ok();
-    let config = envy::from_env::<Config>().wrap_err("cannot get config from env")?;
+    let cli = Cli::parse();
+
+    match run(cli).await {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> eyre::Result<ExitCode> {
+    let sources = match &cli.command {
+        Command::Merge { sources, .. } => sources.clone(),
+        _ => Vec::new(),
+    };
+    // Converting and comparing files works without any configured feed.
+    let needs_feeds = !matches!(cli.command, Command::Convert { .. } | Command::Diff { .. });
+    let config = load_config(cli.config, sources, needs_feeds)?;
+    init_logging(&config);
+    let fetcher = Fetcher::new(&config)?;
+
+    match cli.command {
+        Command::Merge {
+            feed,
+            format,
+            range,
+            output,
+            ..
+        } => {
+            let feed = select_feed(&config, feed.feed)?;
+            let tz = feed.timezone();
+            let (events, failures) = available_events(&fetcher, &config, feed).await;
+
+            let window = match (range.parse_from(tz)?, range.parse_to(tz)?) {
+                (None, None) => feed.window(Utc::now()),
+                (from, to) => Some(TimeWindow {
+                    start: from.unwrap_or_else(|| start_of_day(Utc::now(), tz)),
+                    end: to,
+                }),
+            };
+            let calendar = match format {
+                MergeFormat::Ics => finish_feed(events, &config, feed, window),
+                MergeFormat::Freebusy => freebusy_feed(events, &config, feed, window),
+            };
+
+            output.write(&calendar.to_string())?;
+            Ok(report_failures(&failures))
+        }
+        Command::Validate => {
+            for feed in &config.feeds {
+                println!("{}: {} source(s)", feed.name, feed.sources.len());
+                for source in &feed.sources {
+                    println!("  {}", source.label());
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Stats { feed, range, output } => {
+            let feed = select_feed(&config, feed.feed)?;
+            let tz = feed.timezone();
+            let from = range.parse_from(tz)?.unwrap_or_else(|| start_of_day(Utc::now(), tz));
+            let to = range.parse_to(tz)?.unwrap_or(from + chrono::Duration::days(7));
+            let (events, failures) = available_events(&fetcher, &config, feed).await;
+
+            output.write(&serde_json::to_string_pretty(&feed_stats(&events, feed, from, to))?)?;
+            Ok(report_failures(&failures))
+        }
+        Command::Free {
+            feed,
+            range,
+            duration,
+            format,
+            output,
+        } => {
+            let feed = select_feed(&config, feed.feed)?;
+            let tz = feed.timezone();
+            let from = range.parse_from(tz)?.unwrap_or_else(Utc::now);
+            let to = range.parse_to(tz)?.unwrap_or(from + chrono::Duration::days(7));
+            let duration = match duration {
+                Some(value) => parse_duration_param(&value).ok_or_else(|| eyre!("invalid duration {value}"))?,
+                None => chrono::Duration::zero(),
+            };
+            let (events, failures) = available_events(&fetcher, &config, feed).await;
+
+            let busy = feed_busy_slots(&events.components, &events.holidays, feed, from, to);
+            let slots = free_slots(&busy, &feed_working_periods(feed, from, to), tz, &feed.free, duration);
+
+            let text = match format {
+                FreeFormat::Json => serde_json::to_string_pretty(&slots_to_json(&slots, tz))?,
+                FreeFormat::Ics => with_prodid(slots_to_events(&slots)).to_string(),
+                FreeFormat::Freebusy => {
+                    let periods: Vec<_> = slots.iter().map(|slot| (slot.start, slot.end, "FREE")).collect();
+                    with_prodid(Calendar::from([vfreebusy(from, to, &periods)])).to_string()
+                }
+            };
+
+            output.write(&text)?;
+            Ok(report_failures(&failures))
+        }
+        Command::Convert {
+            input,
+            format,
+            timezone,
+            output,
+        } => {
+            let calendar = load_calendar(&fetcher, &input, config.lenient_parsing).await?;
+
+            let text = match format {
+                ConvertFormat::Ics => with_prodid(calendar).to_string(),
+                ConvertFormat::Json => serde_json::to_string_pretty(&events_to_json(&calendar.components, timezone))?,
+            };
+
+            output.write(&text)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Diff { old, new } => {
+            let old = load_components(&fetcher, &old, config.lenient_parsing).await?;
+            let new = load_components(&fetcher, &new, config.lenient_parsing).await?;
+
+            let changes = diff_calendars(&old, &new);
+            for change in &changes {
+                println!("{change}");
+            }
+
+            Ok(if changes.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(EXIT_DIFFERENT)
+            })
+        }
+        Command::Serve => {
+            start_server(config).await?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+/// The server's configuration, with `--config` or the urls given to `merge`
+/// taking precedence over the environment.
+fn load_config(config_file: Option<PathBuf>, sources: Vec<String>, needs_feeds: bool) -> eyre::Result<Config> {
+    let mut config = Config::from_env().wrap_err("cannot load config")?;
 
-    let mut calendar = urls_to_merged_calendar(config.urls, &config.tz_offsets).await?;
+    if !sources.is_empty() {
+        config.urls = sources;
+        config.config_file = None;
+    } else if let Some(path) = config_file {
+        config.config_file = Some(path);
+    }
 
-    if let Some(days_limit) = config.future_days_limit {
-        calendar = filter_future_days(calendar, days_limit);
+    if !needs_feeds {
+        return Ok(config);
     }
+    config.with_feeds().wrap_err("cannot load config")
+}
+
+fn select_feed(config: &Config, name: Option<String>) -> eyre::Result<&FeedConfig> {
+    match name {
+        Some(name) => config.feed(&name).ok_or_else(|| eyre!("no feed named {name}")),
+        None => Ok(&config.feeds[0]),
+    }
+}
 
-    if config.hide_details {
-        calendar = hide_details(calendar);
+/// Prints the sources that were left out and picks the exit code.
+fn report_failures(failures: &[SourceFailure]) -> ExitCode {
+    for failure in failures {
+        eprintln!("skipped {}: {}", failure.name, failure.error);
     }
 
-    println!("{calendar}");
+    if failures.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_PARTIAL)
+    }
+}
+
+fn with_prodid(mut calendar: Calendar) -> Calendar {
+    set_prodid(&mut calendar);
+    calendar
+}
 
-    Ok(())
+impl RangeArgs {
+    fn parse_from(&self, tz: Tz) -> eyre::Result<Option<DateTime<Utc>>> {
+        parse_time(self.from.as_deref(), tz)
+    }
+
+    fn parse_to(&self, tz: Tz) -> eyre::Result<Option<DateTime<Utc>>> {
+        parse_time(self.to.as_deref(), tz)
+    }
+}
+
+fn parse_time(value: Option<&str>, tz: Tz) -> eyre::Result<Option<DateTime<Utc>>> {
+    value
+        .map(|value| parse_time_param(value, tz).ok_or_else(|| eyre!("invalid date or time {value}")))
+        .transpose()
+}
+
+impl OutputArgs {
+    fn write(&self, text: &str) -> eyre::Result<()> {
+        match &self.output {
+            Some(path) => std::fs::write(path, text).wrap_err_with(|| format!("cannot write {}", path.display())),
+            None => {
+                println!("{text}");
+                Ok(())
+            }
+        }
+    }
 }
@@ -1,9 +1,26 @@
-use ical_merger::lib::{config::Config, error::Result, server::start_server};
+use std::process::ExitCode;
+
+use ical_merger::lib::{config::Config, logging::init_logging, server::start_server};
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     dotenvy::dotenv().ok();
-    let config = envy::from_env::<Config>()?;
 
-    start_server(config).await
+    // Without a config there is no log format yet, so this goes to stderr as is.
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    init_logging(&config);
+
+    match start_server(config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tracing::error!(error = %err, "server stopped");
+            ExitCode::FAILURE
+        }
+    }
 }
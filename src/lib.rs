@@ -1,7 +1,25 @@
 pub mod lib {
+    pub mod alarms;
+    pub mod availability;
+    pub mod busy;
     pub mod calendar;
     pub mod config;
+    pub mod convert;
+    pub mod dedup;
+    pub mod diff;
     pub mod error;
+    pub mod fetch;
+    pub mod filter;
+    pub mod free;
+    pub mod group;
+    pub mod logging;
+    pub mod metrics;
+    pub mod parse;
+    pub mod recurrence;
+    pub mod reload;
+    pub mod rewrite;
     pub mod server;
+    pub mod stats;
     pub mod timezone;
+    pub mod window;
 }
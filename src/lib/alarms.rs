@@ -0,0 +1,169 @@
+use chrono::Duration;
+use icalendar::{Alarm, CalendarComponent, Component, Event, EventLike, Trigger};
+use serde::Deserialize;
+
+use crate::lib::filter::EventMatcher;
+
+/// What happens to the VALARMs of a feed's events.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "mode", rename_all = "lowercase", deny_unknown_fields)]
+pub enum AlarmPolicy {
+    /// Pass the alarms of the sources through.
+    #[default]
+    Keep,
+    /// Remove all alarms.
+    Strip,
+    /// Replace the alarms of the sources by a single display alarm on the
+    /// events matching `when`, or on all events without a condition. Events
+    /// that do not match keep their alarms.
+    Inject {
+        minutes_before: u32,
+
+        #[serde(default = "default_alarm_description")]
+        description: String,
+
+        #[serde(default)]
+        when: Option<Box<EventMatcher>>,
+
+        /// Keep the alarms of the sources and only add one to events that have none.
+        #[serde(default)]
+        keep_existing: bool,
+    },
+}
+
+fn default_alarm_description() -> String {
+    "Reminder".into()
+}
+
+pub fn apply_alarm_policy(components: Vec<CalendarComponent>, policy: &AlarmPolicy) -> Vec<CalendarComponent> {
+    if matches!(policy, AlarmPolicy::Keep) {
+        return components;
+    }
+
+    components
+        .into_iter()
+        .map(|component| match component {
+            CalendarComponent::Event(event) => CalendarComponent::Event(apply_to_event(event, policy)),
+            other => other,
+        })
+        .collect()
+}
+
+fn apply_to_event(event: Event, policy: &AlarmPolicy) -> Event {
+    match policy {
+        AlarmPolicy::Keep => event,
+        AlarmPolicy::Strip => without_alarms(&event),
+        AlarmPolicy::Inject {
+            minutes_before,
+            description,
+            when,
+            keep_existing,
+        } => {
+            if !when.as_ref().is_none_or(|when| when.matches(&event)) {
+                return event;
+            }
+            if *keep_existing && alarm_count(&event) > 0 {
+                return event;
+            }
+
+            let mut event = without_alarms(&event);
+            let trigger = Trigger::before_start(Duration::minutes((*minutes_before).into()));
+            event.alarm(Alarm::display(description, trigger));
+            event
+        }
+    }
+}
+
+pub fn alarm_count(event: &Event) -> usize {
+    event
+        .components()
+        .iter()
+        .filter(|component| component.component_kind() == "VALARM")
+        .count()
+}
+
+/// icalendar cannot remove subcomponents, so the event is rebuilt without its VALARMs.
+fn without_alarms(event: &Event) -> Event {
+    if alarm_count(event) == 0 {
+        return event.clone();
+    }
+
+    let mut stripped = Event::new();
+    for property in event.properties().values() {
+        stripped.append_property(property.clone());
+    }
+    for property in event.multi_properties().values().flatten() {
+        stripped.append_multi_property(property.clone());
+    }
+    for component in event.components() {
+        if component.component_kind() != "VALARM" {
+            stripped.append_component(component.clone());
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(summary: &str) -> CalendarComponent {
+        let text = format!(
+            "BEGIN:VEVENT\r\nUID:{summary}\r\nDTSTART:20240101T100000Z\r\nSUMMARY:{summary}\r\n\
+             ATTENDEE;CN=Alice:mailto:alice@example.com\r\nATTENDEE;CN=Bob:mailto:bob@example.com\r\n\
+             BEGIN:VALARM\r\nACTION:DISPLAY\r\nTRIGGER:-PT1H\r\nEND:VALARM\r\nEND:VEVENT\r\n"
+        );
+        text.parse().unwrap()
+    }
+
+    fn rendered(components: Vec<CalendarComponent>) -> String {
+        components.into_iter().collect::<icalendar::Calendar>().to_string()
+    }
+
+    #[test]
+    fn test_strip_removes_only_alarms() {
+        let output = rendered(apply_alarm_policy(vec![sample("Review")], &AlarmPolicy::Strip));
+
+        assert!(!output.contains("VALARM"));
+        assert_eq!(output.matches("ATTENDEE").count(), 2);
+        assert!(output.contains("SUMMARY:Review"));
+    }
+
+    #[test]
+    fn test_inject_replaces_alarms_on_matching_events() {
+        let policy: AlarmPolicy = toml::from_str(
+            r#"
+            mode = "inject"
+            minutes_before = 10
+            when = { summary = "^Standup$" }
+            "#,
+        )
+        .unwrap();
+
+        let output = apply_alarm_policy(vec![sample("Standup"), sample("Review")], &policy);
+
+        let counts: Vec<usize> = output.iter().map(|c| alarm_count(c.as_event().unwrap())).collect();
+        assert_eq!(counts, vec![1, 1]);
+        let output = rendered(output);
+        assert!(output.contains("TRIGGER;RELATED=START:-PT600S"));
+        assert!(output.contains("TRIGGER:-PT1H"));
+        assert_eq!(output.matches("TRIGGER").count(), 2);
+    }
+
+    #[test]
+    fn test_inject_can_keep_existing_alarms() {
+        let policy = AlarmPolicy::Inject {
+            minutes_before: 10,
+            description: default_alarm_description(),
+            when: None,
+            keep_existing: true,
+        };
+        let bare: CalendarComponent = Event::new().summary("Bare").done().into();
+
+        let output = apply_alarm_policy(vec![sample("Review"), bare], &policy);
+
+        let output = rendered(output);
+        assert!(output.contains("TRIGGER:-PT1H"));
+        assert_eq!(output.matches("TRIGGER;RELATED=START:-PT600S").count(), 1);
+    }
+}
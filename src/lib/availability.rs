@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use icalendar::CalendarComponent;
+use serde::Deserialize;
+
+use crate::lib::busy::{merge_overlapping_slots, BusySlot};
+use crate::lib::recurrence::occurrences_between;
+use crate::lib::timezone::local_to_utc;
+
+/// When the owner of a feed can be booked. Everything outside of it is shown
+/// as unavailable.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Availability {
+    /// Working hours per weekday. Days without an entry are unavailable all day.
+    pub hours: HashMap<Weekday, Vec<TimeRange>>,
+
+    /// Timezone of the working hours, defaults to the feed's timezone.
+    #[serde(default)]
+    pub timezone: Option<String>,
+
+    /// Days off on top of the weekly schedule.
+    #[serde(default)]
+    pub holidays: Vec<NaiveDate>,
+
+    /// Calendar whose events mark days off, e.g. public holidays. If it cannot
+    /// be read, only its days off are missing.
+    #[serde(default)]
+    pub holiday_source: Option<String>,
+
+    /// Summary of the blocks outside working hours.
+    #[serde(default = "default_summary")]
+    pub summary: String,
+}
+
+fn default_summary() -> String {
+    "Unavailable".into()
+}
+
+impl Availability {
+    pub fn timezone(&self, fallback: Tz) -> Tz {
+        self.timezone.as_deref().and_then(|tz| tz.parse().ok()).unwrap_or(fallback)
+    }
+}
+
+/// A span of local time within a day, written as `09:00-17:00`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct TimeRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+        let range = value
+            .split_once('-')
+            .and_then(|(start, end)| Some(TimeRange { start: parse(start)?, end: parse(end)? }));
+
+        match range {
+            Some(range) if range.start < range.end => Ok(range),
+            _ => Err(format!("invalid time range {value:?}, expected something like \"09:00-17:00\"")),
+        }
+    }
+}
+
+/// Blocks outside working hours and on holidays within `[from, to)`.
+/// `holiday_events` are the events of the `holiday_source`; every day they
+/// touch is a day off.
+pub fn unavailable_slots(
+    availability: &Availability,
+    holiday_events: &[CalendarComponent],
+    fallback_tz: Tz,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<BusySlot> {
+    let tz = availability.timezone(fallback_tz);
+    let mut holidays: HashSet<NaiveDate> = availability.holidays.iter().copied().collect();
+    holidays.extend(holiday_dates(holiday_events, tz, from, to));
+
+    let first_day = from.with_timezone(&tz).date_naive();
+    let last_day = to.with_timezone(&tz).date_naive();
+    let mut slots = Vec::new();
+
+    for day in first_day.iter_days().take_while(|day| *day <= last_day) {
+        let (Some(day_start), Some(day_end)) = (
+            local_to_utc(day.and_time(NaiveTime::MIN), tz),
+            local_to_utc((day + Duration::days(1)).and_time(NaiveTime::MIN), tz),
+        ) else {
+            continue;
+        };
+
+        let working = if holidays.contains(&day) {
+            Vec::new()
+        } else {
+            working_hours(availability, day, tz)
+        };
+
+        let mut cursor = day_start;
+        for (work_start, work_end) in working.into_iter().chain(std::iter::once((day_end, day_end))) {
+            let (start, end) = (cursor.max(from), work_start.min(to));
+            if start < end {
+                slots.push(BusySlot {
+                    start,
+                    end,
+                    uid: format!("unavailable-{}", start.format("%Y%m%dT%H%M%SZ")),
+                    labels: Vec::new(),
+                    unavailable: true,
+                });
+            }
+            cursor = cursor.max(work_end);
+        }
+    }
+
+    merge_overlapping_slots(slots, Duration::zero())
+}
+
+/// Working hours within `[from, to)`, without the `holidays`. The days off of the
+/// `holiday_source` are not known here, [`unavailable_slots`] covers them.
+pub fn working_periods(
+    availability: &Availability,
+    fallback_tz: Tz,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let tz = availability.timezone(fallback_tz);
+    let first_day = from.with_timezone(&tz).date_naive();
+    let last_day = to.with_timezone(&tz).date_naive();
+
+    first_day
+        .iter_days()
+        .take_while(|day| *day <= last_day)
+        .filter(|day| !availability.holidays.contains(day))
+        .flat_map(|day| working_hours(availability, day, tz))
+        .filter_map(|(start, end)| {
+            let (start, end) = (start.max(from), end.min(to));
+            (start < end).then_some((start, end))
+        })
+        .collect()
+}
+
+/// The working hours of `day` in `tz`, sorted.
+fn working_hours(availability: &Availability, day: NaiveDate, tz: Tz) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut working: Vec<(DateTime<Utc>, DateTime<Utc>)> = availability
+        .hours
+        .get(&day.weekday())
+        .into_iter()
+        .flatten()
+        .filter_map(|range| Some((local_to_utc(day.and_time(range.start), tz)?, local_to_utc(day.and_time(range.end), tz)?)))
+        .collect();
+    working.sort();
+    working
+}
+
+/// Local dates touched by any occurrence of `events` around `[from, to)`.
+fn holiday_dates(events: &[CalendarComponent], tz: Tz, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<NaiveDate> {
+    events
+        .iter()
+        .filter_map(CalendarComponent::as_event)
+        .flat_map(|event| occurrences_between(event, tz, from - Duration::days(1), to + Duration::days(1)))
+        .flat_map(|occurrence| {
+            let first = occurrence.start.with_timezone(&tz).date_naive();
+            let last = (occurrence.end - Duration::seconds(1)).with_timezone(&tz).date_naive();
+            first.iter_days().take_while(move |day| *day <= last.max(first))
+        })
+        .collect()
+}
+
+/// Puts the real busy slots and the unavailable blocks on one timeline.
+///
+/// Unavailable time wins: busy slots are cut where they overlap it, so every
+/// instant is covered by at most one slot.
+pub fn with_unavailable(busy: Vec<BusySlot>, unavailable: Vec<BusySlot>) -> Vec<BusySlot> {
+    let mut slots = Vec::new();
+
+    for slot in busy {
+        let mut start = slot.start;
+        for blocked in unavailable.iter().filter(|blocked| blocked.end > slot.start && blocked.start < slot.end) {
+            if blocked.start > start {
+                slots.push(piece(&slot, start, blocked.start));
+            }
+            start = start.max(blocked.end);
+        }
+        if start < slot.end {
+            slots.push(piece(&slot, start, slot.end));
+        }
+    }
+
+    slots.extend(unavailable);
+    slots.sort_by_key(|slot| slot.start);
+    slots
+}
+
+/// The part of `slot` from `start` to `end`, with a UID of its own unless it
+/// starts where the slot does.
+fn piece(slot: &BusySlot, start: DateTime<Utc>, end: DateTime<Utc>) -> BusySlot {
+    BusySlot {
+        start,
+        end,
+        uid: if start == slot.start {
+            slot.uid.clone()
+        } else {
+            format!("{}-{}", slot.uid, start.format("%Y%m%dT%H%M%SZ"))
+        },
+        labels: slot.labels.clone(),
+        unavailable: slot.unavailable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use icalendar::{Event, EventLike};
+
+    fn availability() -> Availability {
+        toml::from_str(
+            r#"
+            holidays = ["2024-03-19"]
+            hours = { mon = ["09:00-12:00", "13:00-17:00"], tue = ["09:00-17:00"], wed = ["09:00-17:00"] }
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn spans(slots: &[BusySlot]) -> Vec<String> {
+        slots
+            .iter()
+            .map(|slot| format!("{}-{}", slot.start.format("%a %H:%M"), slot.end.format("%a %H:%M")))
+            .collect()
+    }
+
+    #[test]
+    fn test_blocks_outside_working_hours_and_on_holidays() {
+        // Monday 2024-03-18 to Thursday; Tuesday is a holiday and Wednesday comes from the holiday calendar.
+        let from = Utc.with_ymd_and_hms(2024, 3, 18, 0, 0, 0).unwrap();
+        let to = Utc.with_ymd_and_hms(2024, 3, 21, 12, 0, 0).unwrap();
+        let source: Vec<CalendarComponent> =
+            vec![Event::new().all_day(NaiveDate::from_ymd_opt(2024, 3, 20).unwrap()).done().into()];
+
+        let slots = unavailable_slots(&availability(), &source, Tz::UTC, from, to);
+
+        assert_eq!(spans(&slots), vec!["Mon 00:00-Mon 09:00", "Mon 12:00-Mon 13:00", "Mon 17:00-Thu 12:00"]);
+        assert!(slots.iter().all(|slot| slot.unavailable));
+    }
+
+    #[test]
+    fn test_working_periods_follow_the_weekly_hours() {
+        let from = Utc.with_ymd_and_hms(2024, 3, 18, 10, 0, 0).unwrap();
+        let to = Utc.with_ymd_and_hms(2024, 3, 21, 0, 0, 0).unwrap();
+
+        let periods = working_periods(&availability(), Tz::UTC, from, to);
+
+        let spans: Vec<String> = periods
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start.format("%a %H:%M"), end.format("%a %H:%M")))
+            .collect();
+        assert_eq!(spans, vec!["Mon 10:00-Mon 12:00", "Mon 13:00-Mon 17:00", "Wed 09:00-Wed 17:00"]);
+    }
+
+    #[test]
+    fn test_time_range_rejects_inverted_ranges() {
+        assert!(toml::from_str::<Availability>(r#"hours = { mon = ["17:00-09:00"] }"#).is_err());
+        assert!(toml::from_str::<Availability>(r#"hours = { mon = ["9-5"] }"#).is_err());
+    }
+
+    #[test]
+    fn test_busy_slots_are_cut_at_unavailable_time() {
+        let at = |hour| Utc.with_ymd_and_hms(2024, 3, 18, hour, 0, 0).unwrap();
+        let slot = |uid: &str, start, end, unavailable| BusySlot {
+            start: at(start),
+            end: at(end),
+            uid: uid.into(),
+            labels: Vec::new(),
+            unavailable,
+        };
+
+        let slots = with_unavailable(
+            vec![slot("late", 16, 19, false), slot("lunch", 12, 13, false)],
+            vec![slot("evening", 17, 23, true)],
+        );
+
+        assert_eq!(spans(&slots), vec!["Mon 12:00-Mon 13:00", "Mon 16:00-Mon 17:00", "Mon 17:00-Mon 23:00"]);
+        let uids: Vec<&str> = slots.iter().map(|slot| slot.uid.as_str()).collect();
+        assert_eq!(uids, vec!["lunch", "late", "evening"]);
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use icalendar::{CalendarComponent, Component, Event, EventLike, Property};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::lib::calendar::SOURCE_PROPERTY;
+use crate::lib::recurrence::{occurrences, occurrences_between};
+
+/// How busy blocks are computed when details are hidden.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BusyOptions {
+    /// Name busy blocks after the sources they come from instead of calling
+    /// them "Blocked".
+    #[serde(default)]
+    pub summary_from_source: bool,
+
+    /// Leave out all-day events, which are often just reminders.
+    #[serde(default)]
+    pub exclude_all_day: bool,
+
+    /// How many days ahead recurring events are expanded if the feed has no
+    /// `future_days`.
+    #[serde(default = "default_expand_days")]
+    pub expand_days: u32,
+
+    /// Minutes added before every timed event, e.g. for travel or preparation.
+    #[serde(default)]
+    pub padding_before_mins: u32,
+
+    #[serde(default)]
+    pub padding_after_mins: u32,
+
+    /// Replaces `padding_before_mins` for events with a LOCATION.
+    #[serde(default)]
+    pub location_padding_before_mins: Option<u32>,
+
+    /// Replaces `padding_after_mins` for events with a LOCATION.
+    #[serde(default)]
+    pub location_padding_after_mins: Option<u32>,
+
+    /// Busy blocks less than this many minutes apart are joined, so no tiny
+    /// free slivers are left between them.
+    #[serde(default)]
+    pub merge_gaps_shorter_than_mins: u32,
+}
+
+impl BusyOptions {
+    /// Padding before and after `event`. All-day events are never padded.
+    fn padding(&self, event: &Event) -> (Duration, Duration) {
+        let (before, after) = match event.get_location() {
+            Some(location) if !location.trim().is_empty() => (
+                self.location_padding_before_mins.unwrap_or(self.padding_before_mins),
+                self.location_padding_after_mins.unwrap_or(self.padding_after_mins),
+            ),
+            _ => (self.padding_before_mins, self.padding_after_mins),
+        };
+
+        (Duration::minutes(before.into()), Duration::minutes(after.into()))
+    }
+}
+
+impl Default for BusyOptions {
+    fn default() -> Self {
+        BusyOptions {
+            summary_from_source: false,
+            exclude_all_day: false,
+            expand_days: default_expand_days(),
+            padding_before_mins: 0,
+            padding_after_mins: 0,
+            location_padding_before_mins: None,
+            location_padding_after_mins: None,
+            merge_gaps_shorter_than_mins: 0,
+        }
+    }
+}
+
+fn default_expand_days() -> u32 {
+    14
+}
+
+/// A span of time in which at least one event takes place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusySlot {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub uid: String,
+    /// Sources of the events covered by this slot.
+    pub labels: Vec<String>,
+    /// Outside working hours or on a holiday rather than taken by an event.
+    pub unavailable: bool,
+}
+
+/// Computes the merged busy slots of all events.
+///
+/// Recurring events are expanded within `[from, to)`, single events are always
+/// included. All-day events cover whole days in `tz`, which is also used for
+/// floating times.
+pub fn busy_slots(
+    components: &[CalendarComponent],
+    options: &BusyOptions,
+    tz: Tz,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<BusySlot> {
+    let slots = busy_events(components)
+        .into_iter()
+        .flat_map(|event| {
+            let (before, after) = options.padding(&event);
+            event_slots(&event, tz, from, to)
+                .into_iter()
+                .filter(|(slot, all_day)| !(options.exclude_all_day && *all_day) && slot.end > slot.start)
+                .map(move |(mut slot, all_day)| {
+                    if !all_day {
+                        slot.start -= before;
+                        slot.end += after;
+                    }
+                    slot
+                })
+        })
+        .collect();
+
+    merge_overlapping_slots(slots, Duration::minutes(options.merge_gaps_shorter_than_mins.into()))
+}
+
+/// The events that take up time, ready to be expanded.
+///
+/// Events with a RECURRENCE-ID replace that instance of the series with the
+/// same UID, so the series skips it like an EXDATE. Cancelled and transparent
+/// events are left out, after the instances they replace have been removed.
+pub fn busy_events(components: &[CalendarComponent]) -> Vec<Event> {
+    let events: Vec<&Event> = components.iter().filter_map(CalendarComponent::as_event).collect();
+
+    let mut overridden: HashMap<&str, Vec<&Property>> = HashMap::new();
+    for event in &events {
+        if let (Some(uid), Some(id)) = (event.get_uid(), event.properties().get("RECURRENCE-ID")) {
+            overridden.entry(uid).or_default().push(id);
+        }
+    }
+
+    events
+        .into_iter()
+        .filter(|event| takes_time(event))
+        .map(|event| {
+            let mut busy = event.clone();
+            let overrides = event.get_uid().and_then(|uid| overridden.get(uid));
+            if let (Some(overrides), false) = (overrides, event.properties().contains_key("RECURRENCE-ID")) {
+                for id in overrides {
+                    let mut exdate = Property::new("EXDATE", id.value());
+                    for parameter in id.params().values().filter(|parameter| parameter.key() != "RANGE") {
+                        exdate.append_parameter(parameter.clone());
+                    }
+                    busy.append_multi_property(exdate);
+                }
+            }
+            busy
+        })
+        .collect()
+}
+
+fn takes_time(event: &Event) -> bool {
+    let is = |key: &str, value: &str| event.property_value(key).is_some_and(|v| v.eq_ignore_ascii_case(value));
+    !is("STATUS", "CANCELLED") && !is("TRANSP", "TRANSPARENT")
+}
+
+fn event_slots(event: &Event, tz: Tz, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<(BusySlot, bool)> {
+    let uid = event
+        .get_uid()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("generated-uid-{}", Uuid::new_v4()));
+    let labels: Vec<String> = event.property_value(SOURCE_PROPERTY).map(str::to_string).into_iter().collect();
+
+    if event.property_value("RRULE").is_none() {
+        return occurrences(event, tz)
+            .map(|occurrence| {
+                let slot = BusySlot {
+                    start: occurrence.start,
+                    end: occurrence.end,
+                    uid: uid.clone(),
+                    labels: labels.clone(),
+                    unavailable: false,
+                };
+                (slot, occurrence.all_day)
+            })
+            .collect();
+    }
+
+    occurrences_between(event, tz, from, to)
+        .into_iter()
+        .map(|occurrence| {
+            let slot = BusySlot {
+                start: occurrence.start,
+                end: occurrence.end,
+                uid: format!("{uid}-{}", occurrence.start.format("%Y%m%dT%H%M%SZ")),
+                labels: labels.clone(),
+                unavailable: false,
+            };
+            (slot, occurrence.all_day)
+        })
+        .collect()
+}
+
+/// Merges overlapping and adjacent slots, and slots less than `min_gap` apart,
+/// keeping the UID of the earliest one.
+pub fn merge_overlapping_slots(mut slots: Vec<BusySlot>, min_gap: Duration) -> Vec<BusySlot> {
+    slots.sort_by_key(|slot| slot.start);
+
+    let mut merged: Vec<BusySlot> = Vec::new();
+    for slot in slots {
+        match merged.last_mut() {
+            Some(current) if current.end >= slot.start || slot.start - current.end < min_gap => {
+                current.end = current.end.max(slot.end);
+                current.unavailable &= slot.unavailable;
+                for label in slot.labels {
+                    if !current.labels.contains(&label) {
+                        current.labels.push(label);
+                    }
+                }
+            }
+            _ => merged.push(slot),
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone};
+
+    fn components() -> Vec<CalendarComponent> {
+        let start = Utc.with_ymd_and_hms(2024, 3, 15, 9, 0, 0).unwrap();
+        vec![
+            Event::new()
+                .uid("holiday")
+                .all_day(NaiveDate::from_ymd_opt(2024, 3, 15).unwrap())
+                .done()
+                .into(),
+            Event::new()
+                .uid("call")
+                .starts(start)
+                .add_property("DURATION", "PT30M")
+                .done()
+                .into(),
+            Event::new()
+                .uid("trip")
+                .starts(NaiveDate::from_ymd_opt(2024, 3, 18).unwrap())
+                .ends(NaiveDate::from_ymd_opt(2024, 3, 20).unwrap())
+                .done()
+                .into(),
+        ]
+    }
+
+    fn range() -> (DateTime<Utc>, DateTime<Utc>) {
+        let from = Utc.with_ymd_and_hms(2024, 3, 15, 0, 0, 0).unwrap();
+        (from, from + Duration::days(14))
+    }
+
+    #[test]
+    fn test_all_day_events_span_whole_days_in_target_timezone() {
+        let (from, to) = range();
+        let slots = busy_slots(&components(), &BusyOptions::default(), chrono_tz::Europe::Berlin, from, to);
+
+        let spans: Vec<_> = slots.iter().map(|slot| (slot.start, slot.end)).collect();
+        assert_eq!(
+            spans,
+            vec![
+                (
+                    Utc.with_ymd_and_hms(2024, 3, 14, 23, 0, 0).unwrap(),
+                    Utc.with_ymd_and_hms(2024, 3, 15, 23, 0, 0).unwrap()
+                ),
+                (
+                    Utc.with_ymd_and_hms(2024, 3, 17, 23, 0, 0).unwrap(),
+                    Utc.with_ymd_and_hms(2024, 3, 19, 23, 0, 0).unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exclude_all_day_keeps_duration_events() {
+        let (from, to) = range();
+        let options = BusyOptions {
+            exclude_all_day: true,
+            ..Default::default()
+        };
+        let slots = busy_slots(&components(), &options, Tz::UTC, from, to);
+
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0].uid, "call");
+        assert_eq!(slots[0].end - slots[0].start, Duration::minutes(30));
+    }
+
+    #[test]
+    fn test_padding_and_gap_merging() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 15, 9, 0, 0).unwrap();
+        let meeting = |uid: &str, hour: i64, location: Option<&str>| -> CalendarComponent {
+            let mut event = Event::new();
+            event
+                .uid(uid)
+                .starts(start + Duration::hours(hour))
+                .ends(start + Duration::hours(hour) + Duration::minutes(30));
+            if let Some(location) = location {
+                event.location(location);
+            }
+            event.done().into()
+        };
+        let components = vec![meeting("a", 0, None), meeting("b", 1, Some("Office")), meeting("c", 3, None)];
+        let (from, to) = range();
+
+        let padded = BusyOptions {
+            padding_before_mins: 15,
+            padding_after_mins: 10,
+            location_padding_before_mins: Some(20),
+            ..Default::default()
+        };
+        let spans: Vec<_> = busy_slots(&components, &padded, Tz::UTC, from, to)
+            .iter()
+            .map(|slot| (slot.start.format("%H:%M").to_string(), slot.end.format("%H:%M").to_string()))
+            .collect();
+        // 09:00-09:30 becomes 08:45-09:40, 10:00-10:30 at the office 09:40-10:40.
+        assert_eq!(spans, vec![("08:45".into(), "10:40".into()), ("11:45".into(), "12:40".into())]);
+
+        let merged = BusyOptions {
+            merge_gaps_shorter_than_mins: 90,
+            ..padded
+        };
+        assert_eq!(busy_slots(&components, &merged, Tz::UTC, from, to).len(), 1);
+    }
+
+    #[test]
+    fn test_moved_and_cancelled_instances() {
+        let text = "BEGIN:VCALENDAR\r\n\
+                    BEGIN:VEVENT\r\nUID:standup\r\nDTSTART:20240315T090000Z\r\nDTEND:20240315T093000Z\r\n\
+                    RRULE:FREQ=DAILY;COUNT=3\r\nEND:VEVENT\r\n\
+                    BEGIN:VEVENT\r\nUID:standup\r\nRECURRENCE-ID:20240316T090000Z\r\n\
+                    DTSTART:20240316T140000Z\r\nDTEND:20240316T143000Z\r\nEND:VEVENT\r\n\
+                    BEGIN:VEVENT\r\nUID:standup\r\nRECURRENCE-ID:20240317T090000Z\r\n\
+                    DTSTART:20240317T090000Z\r\nDTEND:20240317T093000Z\r\nSTATUS:CANCELLED\r\nEND:VEVENT\r\n\
+                    BEGIN:VEVENT\r\nUID:focus\r\nDTSTART:20240318T090000Z\r\nDTEND:20240318T120000Z\r\n\
+                    TRANSP:TRANSPARENT\r\nEND:VEVENT\r\n\
+                    END:VCALENDAR\r\n";
+        let components = text.parse::<icalendar::Calendar>().unwrap().components;
+        let (from, to) = range();
+
+        let starts: Vec<_> = busy_slots(&components, &BusyOptions::default(), Tz::UTC, from, to)
+            .iter()
+            .map(|slot| slot.start)
+            .collect();
+
+        assert_eq!(
+            starts,
+            vec![
+                Utc.with_ymd_and_hms(2024, 3, 15, 9, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2024, 3, 16, 14, 0, 0).unwrap(),
+            ]
+        );
+    }
+}
@@ -1,428 +1,478 @@
 use futures::stream::FuturesOrdered;
 use futures::StreamExt;
-use icalendar::{parser::read_calendar, Calendar, CalendarComponent, Component, Event, EventLike, DatePerhapsTime, CalendarDateTime};
-use uuid::Uuid;
-use chrono::{Local, NaiveDateTime, Weekday, Datelike};
-
+use tracing::Instrument;
+use icalendar::{Calendar, CalendarComponent, Component, Event, EventLike, Property};
+use chrono::{DateTime, Utc};
+
+use crate::lib::alarms::apply_alarm_policy;
+use crate::lib::availability::{unavailable_slots, with_unavailable, working_periods};
+use crate::lib::busy::{busy_slots, BusySlot};
+use crate::lib::config::{host_label, CalendarMetadata, Config, FeedConfig, SourceConfig};
+use crate::lib::dedup::{deduplicate, DedupOptions};
 use crate::lib::error::{Error, Result};
-use crate::lib::timezone::shift_timezone;
-
-async fn url_to_text(url: String) -> Result<String> {
-    let client = reqwest::Client::builder()
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36")
-        .timeout(std::time::Duration::from_secs(30))
-        .build()
-        .map_err(Error::Reqwest)?;
-
-    let res = client
-        .get(&url)
-        .header("Accept", "text/calendar,application/calendar,text/plain,*/*")
-        .header("Accept-Language", "en-US,en;q=0.9")
-        .header("Cache-Control", "no-cache")
-        .header("Connection", "keep-alive")
-        .send()
-        .await
-        .map_err(Error::Reqwest)?;
-
-    if !res.status().is_success() {
-        return Err(Error::ParseCalender(format!(
-            "HTTP {} error for URL {}: {}",
-            res.status(),
-            url,
-            res.text().await.unwrap_or_else(|_| "Unknown error".to_string())
-        )));
+use crate::lib::fetch::{FetchedBody, Fetcher};
+use crate::lib::filter::apply_filters;
+use crate::lib::free::{self, vfreebusy};
+use crate::lib::parse::{check_durations, decode_body, parse_lenient, parse_strict, ParseWarning};
+use crate::lib::rewrite::apply_rewrites;
+use crate::lib::timezone::{normalize_timezones, shift_timezone};
+use crate::lib::window::{apply_time_window, start_of_day, TimeWindow};
+
+pub const PRODID: &str = concat!("-//dattito//ical-merger ", env!("CARGO_PKG_VERSION"), "//EN");
+
+/// Names the source an event was merged from.
+pub const SOURCE_PROPERTY: &str = "X-ICAL-MERGER-SOURCE";
+
+/// Name of an availability's `holiday_source` in logs and status.
+pub const HOLIDAY_SOURCE: &str = "holidays";
+
+async fn url_to_components(fetcher: &Fetcher, source: &str, url: &str, lenient: bool) -> Result<Vec<CalendarComponent>> {
+    Ok(url_to_calendar(fetcher, source, url, lenient).await?.components)
+}
+
+/// Fetches and parses `url`. `source` is its label, which unlike the url is
+/// safe to show in metrics.
+async fn url_to_calendar(fetcher: &Fetcher, source: &str, url: &str, lenient: bool) -> Result<Calendar> {
+    let failed = |body: Option<&FetchedBody>, err: &Error| {
+        tracing::warn!(error = %err, kind = err.kind(), "cannot read source");
+        fetcher.record_source_error(source, url, body, err);
+    };
+
+    let body = fetcher.fetch(source, url).await.inspect_err(|err| failed(None, err))?;
+    let (calendar, warnings) = parse_body(&body.bytes, body.content_type.as_deref(), lenient)
+        .inspect_err(|err| failed(Some(&body), err))?;
+
+    if !warnings.is_empty() {
+        tracing::warn!(warnings = warnings.len(), "problems while reading source, see /diagnostics");
     }
+    tracing::debug!(bytes = body.bytes.len(), components = calendar.components.len(), "read source");
+    fetcher.record_source_success(url, &body, calendar.components.len(), warnings);
 
-    res.text().await.map_err(Error::Reqwest)
+    Ok(calendar)
 }
 
-fn text_to_calender(text: String) -> Result<Calendar> {
-    let text_unfolded = icalendar::parser::unfold(&text);
-    let parsed_calender = read_calendar(&text_unfolded).map_err(Error::ParseCalender)?;
+/// Reads a calendar from an http(s) url or, for anything else, a local file.
+pub async fn load_components(fetcher: &Fetcher, location: &str, lenient: bool) -> Result<Vec<CalendarComponent>> {
+    Ok(load_calendar(fetcher, location, lenient).await?.components)
+}
 
-    let calendar = Calendar::from(parsed_calender);
+/// Like [`load_components`], but keeps the calendar level properties like
+/// X-WR-CALNAME.
+pub async fn load_calendar(fetcher: &Fetcher, location: &str, lenient: bool) -> Result<Calendar> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return url_to_calendar(fetcher, &host_label(location), location, lenient).await;
+    }
+
+    let bytes = tokio::fs::read(location)
+        .await
+        .map_err(|e| Error::ReadFile(location.to_string(), e))?;
+    let (calendar, warnings) = parse_body(&bytes, None, lenient).map_err(|err| match err {
+        Error::ParseCalender(e) => Error::ParseCalender(format!("{location}: {e}")),
+        err => err,
+    })?;
+
+    if !warnings.is_empty() {
+        tracing::warn!(location, warnings = warnings.len(), "problems while reading file");
+    }
 
     Ok(calendar)
 }
 
-async fn url_to_components(url: String) -> Result<Vec<CalendarComponent>> {
-    let text = url_to_text(url).await?;
+fn parse_body(bytes: &[u8], content_type: Option<&str>, lenient: bool) -> Result<(Calendar, Vec<ParseWarning>)> {
+    let (text, mut warnings) = decode_body(bytes, content_type);
+
+    let calendar = if lenient {
+        let (calendar, parse_warnings) = parse_lenient(&text);
+        warnings.extend(parse_warnings);
+        calendar
+    } else {
+        parse_strict(&text).map_err(Error::ParseCalender)?
+    };
+    warnings.extend(check_durations(&calendar));
+
+    Ok((calendar, warnings))
+}
 
-    Ok(text_to_calender(text)?.components)
+/// A source that could not be read while merging.
+#[derive(Debug)]
+pub struct SourceFailure {
+    /// The label of the source, see [`SourceConfig::label`].
+    pub name: String,
+    pub error: Error,
 }
 
-pub async fn urls_to_merged_calendar(urls: Vec<String>, offsets: &[i64]) -> Result<Calendar> {
-    let calendar = urls
-        .into_iter()
-        .enumerate()
-        .map(|(index, url)| async move {
-            let components = url_to_components(url).await?;
-
-            if offsets.is_empty() {
-                Ok(components)
-            } else if index >= offsets.len() {
-                Ok(shift_timezone(components, *offsets.last().unwrap()).components)
-            } else {
-                Ok(shift_timezone(components, *offsets.get(index).unwrap()).components)
+impl SourceFailure {
+    pub fn into_error(self) -> Error {
+        Error::Source(self.name, Box::new(self.error))
+    }
+}
+
+pub async fn urls_to_merged_calendar(
+    fetcher: &Fetcher,
+    sources: &[SourceConfig],
+    lenient: bool,
+    dedup: &DedupOptions,
+) -> Result<Calendar> {
+    let (calendar, failures) = merge_available_sources(fetcher, sources, lenient, dedup).await;
+
+    match failures.into_iter().next() {
+        Some(failure) => Err(failure.into_error()),
+        None => Ok(calendar),
+    }
+}
+
+/// Merges the sources that can be read and reports the others instead of
+/// failing as a whole.
+pub async fn merge_available_sources(
+    fetcher: &Fetcher,
+    sources: &[SourceConfig],
+    lenient: bool,
+    dedup: &DedupOptions,
+) -> (Calendar, Vec<SourceFailure>) {
+    let results = sources
+        .iter()
+        .map(|source| {
+            let span = tracing::info_span!("source", source = %source.label());
+            async move {
+                let mut components = url_to_components(fetcher, &source.label(), &source.url, lenient).await?;
+
+                if source.tz_offset != 0 {
+                    components = shift_timezone(components, source.tz_offset).components;
+                }
+
+                Ok(apply_rewrites(apply_filters(components, &source.filters), &source.rewrites))
             }
+            .instrument(span)
         })
         .collect::<FuturesOrdered<_>>()
-        .collect::<Vec<_>>()
-        .await
+        .collect::<Vec<Result<Vec<CalendarComponent>>>>()
+        .await;
+
+    // Failed sources contribute nothing, but keep their index for tagging.
+    let mut failures = Vec::new();
+    let fetched = results
         .into_iter()
-        .collect::<Result<Vec<_>>>()?
+        .zip(sources)
+        .map(|(result, source)| {
+            result.unwrap_or_else(|error| {
+                failures.push(SourceFailure {
+                    name: source.label(),
+                    error,
+                });
+                Vec::new()
+            })
+        })
+        .collect();
+
+    let calendar = deduplicate(fetched, dedup)
         .into_iter()
-        .flatten()
+        .map(|(index, component)| tag_with_source(component, &sources[index]))
         .collect::<Calendar>();
 
-    // let calendar = join_all(calendars)
-    //     .await
-    //     .into_iter()
-    //     .collect::<Result<Vec<Vec<CalendarComponent>>>>()?
-    //     .into_iter()
-    //     .flatten()
-    //     .collect::<Calendar>();
+    (calendar, failures)
+}
 
-    Ok(calendar)
+/// Marks an event with the source it came from and applies the source's
+/// summary prefix, categories and color.
+fn tag_with_source(component: CalendarComponent, source: &SourceConfig) -> CalendarComponent {
+    let CalendarComponent::Event(mut event) = component else {
+        return component;
+    };
+
+    if let Some(prefix) = &source.summary_prefix {
+        let summary = match event.get_summary() {
+            Some(summary) => format!("{prefix} {summary}"),
+            None => prefix.clone(),
+        };
+        event.summary(&summary);
+    }
+
+    for category in &source.categories {
+        event.append_multi_property(("CATEGORIES", category.as_str()));
+    }
+
+    if let Some(color) = &source.color {
+        event.add_property("COLOR", color);
+    }
+
+    event.add_property(SOURCE_PROPERTY, source.label());
+    CalendarComponent::Event(event)
 }
 
-pub async fn calendars_to_merged_calendar(calendars: Vec<Calendar>) -> Calendar {
-    calendars
-        .into_iter()
-        .flat_map(|c| c.components)
-        .collect::<Calendar>()
+/// Builds the calendar served for `feed`, from fetching its sources to the final metadata.
+pub async fn build_feed(fetcher: &Fetcher, config: &Config, feed: &FeedConfig) -> Result<Calendar> {
+    let events = merged_events(fetcher, config, feed).await?;
+    Ok(finish_feed(events, config, feed, feed.window(Utc::now())))
 }
 
-pub fn hide_details(calendar: Calendar) -> Calendar {
-    let mut all_event_slots = Vec::new();
-    let mut non_events = Vec::new();
-
-    // Define the 14-day window for recurring event expansion
-    let window_start = Local::now().naive_local();
-    let window_end = window_start + chrono::Duration::days(14);
-
-    // Process all events and expand recurring ones
-    for component in calendar.components {
-        if let Some(event) = component.as_event() {
-            // Only process events that have both start and end times
-            if let (Some(start), Some(end)) = (event.get_start(), event.get_end()) {
-                if let (Some(start_dt), Some(end_dt)) = (extract_naive_datetime(&start), extract_naive_datetime(&end)) {
-                    let uid = event.get_uid().map(|s| s.to_string()).unwrap_or_else(|| format!("generated-uid-{}", Uuid::new_v4()));
-
-                    // Check if this is a recurring event
-                    if let Some(rrule) = event.property_value("RRULE") {
-                        // Expand recurring event into individual occurrences
-                        let expanded_occurrences = expand_recurring_event(
-                            start_dt,
-                            end_dt,
-                            rrule,
-                            window_start,
-                            window_end,
-                        );
-                        all_event_slots.extend(expanded_occurrences);
-                    } else {
-                        // For single events, add directly
-                        all_event_slots.push(EventTimeSlot {
-                            start: start_dt,
-                            end: end_dt,
-                            uid,
-                        });
-                    }
-                }
-            }
-        } else {
-            // Keep non-event components (VTIMEZONE, etc.)
-            non_events.push(component);
-        }
+/// Everything fetched for a feed, before any time window or privacy transform.
+#[derive(Debug, Clone, Default)]
+pub struct FeedEvents {
+    /// The merged and filtered components of all sources.
+    pub components: Vec<CalendarComponent>,
+    /// The events of the availability's `holiday_source`.
+    pub holidays: Vec<CalendarComponent>,
+}
+
+pub async fn merged_events(fetcher: &Fetcher, config: &Config, feed: &FeedConfig) -> Result<FeedEvents> {
+    all_or_nothing(merge_feed(fetcher, config, feed, &config.dedup_options()).await)
+}
+
+/// Like [`merged_events`], but without deduplication, so that every source
+/// keeps its own copy of a shared event. Used to tell people apart in group
+/// availability.
+pub async fn group_events(fetcher: &Fetcher, config: &Config, feed: &FeedConfig) -> Result<FeedEvents> {
+    let dedup = DedupOptions {
+        enabled: false,
+        ..config.dedup_options()
+    };
+    all_or_nothing(merge_feed(fetcher, config, feed, &dedup).await)
+}
+
+/// Like [`merged_events`], but leaves out the sources that cannot be read and
+/// reports them next to the result.
+pub async fn available_events(fetcher: &Fetcher, config: &Config, feed: &FeedConfig) -> (FeedEvents, Vec<SourceFailure>) {
+    merge_feed(fetcher, config, feed, &config.dedup_options()).await
+}
+
+fn all_or_nothing((events, failures): (FeedEvents, Vec<SourceFailure>)) -> Result<FeedEvents> {
+    match failures.into_iter().next() {
+        Some(failure) => Err(failure.into_error()),
+        None => Ok(events),
     }
+}
 
-    // Merge ALL overlapping events (both single and expanded recurring)
-    let merged_events = merge_overlapping_events(all_event_slots);
+async fn merge_feed(
+    fetcher: &Fetcher,
+    config: &Config,
+    feed: &FeedConfig,
+    dedup: &DedupOptions,
+) -> (FeedEvents, Vec<SourceFailure>) {
+    let (calendar, failures) = merge_available_sources(fetcher, &feed.sources, config.lenient_parsing, dedup).await;
+
+    // Without holidays the feed is still useful, so they are only left out.
+    let holidays = match feed.availability.as_ref().and_then(|availability| availability.holiday_source.as_deref()) {
+        Some(url) => url_to_components(fetcher, HOLIDAY_SOURCE, url, config.lenient_parsing)
+            .instrument(tracing::info_span!("source", source = HOLIDAY_SOURCE))
+            .await
+            .unwrap_or_else(|error| {
+                tracing::warn!(source = HOLIDAY_SOURCE, error = %error, "leaving out holidays");
+                Vec::new()
+            }),
+        None => Vec::new(),
+    };
 
-    // Create new calendar with merged events
-    let mut calendar_components = non_events;
+    let events = FeedEvents {
+        components: apply_filters(calendar.components, &feed.filters),
+        holidays,
+    };
+    (events, failures)
+}
 
-    for event_slot in merged_events {
-        let mut new_event = Event::new();
+/// Turns the output of [`merged_events`] into the calendar served for `feed`,
+/// keeping only what overlaps `window`.
+pub fn finish_feed(events: FeedEvents, config: &Config, feed: &FeedConfig, window: Option<TimeWindow>) -> Calendar {
+    let mut calendar: Calendar = apply_alarm_policy(events.components, &feed.alarms).into_iter().collect();
 
-        new_event.uid(&event_slot.uid);
-        new_event.starts(DatePerhapsTime::DateTime(CalendarDateTime::Floating(event_slot.start)));
-        new_event.ends(DatePerhapsTime::DateTime(CalendarDateTime::Floating(event_slot.end)));
-        new_event.summary("Blocked");
-        new_event.status(icalendar::EventStatus::Confirmed);
+    let tz = feed.timezone();
+    if let Some(window) = &window {
+        calendar = apply_time_window(calendar, window, tz);
+    }
 
-        calendar_components.push(CalendarComponent::Event(new_event.done()));
+    if feed.hide_details {
+        let (from, to) = busy_range(feed, window);
+        let slots = feed_busy_slots(&calendar.components, &events.holidays, feed, from, to);
+        calendar = hide_details(calendar, slots, feed);
     }
 
-    calendar_components.into_iter().collect::<Calendar>()
+    let refresh_interval = chrono::Duration::seconds(config.refresh_interval_secs as i64);
+
+    apply_metadata(normalize_timezones(calendar), &feed.calendar, refresh_interval)
 }
 
-fn extract_naive_datetime(date_time: &DatePerhapsTime) -> Option<NaiveDateTime> {
-    match date_time {
-        DatePerhapsTime::DateTime(calendar_dt) => {
-            match calendar_dt {
-                CalendarDateTime::Floating(naive_dt) => Some(*naive_dt),
-                CalendarDateTime::Utc(utc_dt) => Some(utc_dt.naive_local()),
-                CalendarDateTime::WithTimezone { date_time, .. } => Some(*date_time),
-            }
-        },
-        DatePerhapsTime::Date(date) => {
-            // Convert date to datetime at start of day
-            Some(date.and_hms_opt(0, 0, 0)?)
-        },
-    }
+/// The busy time of `feed` as a single VFREEBUSY over the range its busy
+/// blocks are computed for.
+pub fn freebusy_feed(events: FeedEvents, config: &Config, feed: &FeedConfig, window: Option<TimeWindow>) -> Calendar {
+    let (from, to) = busy_range(feed, window);
+
+    let periods: Vec<_> = feed_busy_slots(&events.components, &events.holidays, feed, from, to)
+        .into_iter()
+        .filter(|slot| slot.end > from && slot.start < to)
+        .map(|slot| {
+            let fbtype = if slot.unavailable { "BUSY-UNAVAILABLE" } else { "BUSY" };
+            (slot.start.max(from), slot.end.min(to), fbtype)
+        })
+        .collect();
+
+    let calendar = Calendar::from([vfreebusy(from, to, &periods)]);
+    let refresh_interval = chrono::Duration::seconds(config.refresh_interval_secs as i64);
+
+    apply_metadata(calendar, &feed.calendar, refresh_interval)
 }
 
-#[derive(Debug, Clone)]
-struct EventTimeSlot {
-    start: NaiveDateTime,
-    end: NaiveDateTime,
-    uid: String,
+/// Range in which recurring events are expanded into busy blocks: the feed's
+/// time window, or `expand_days` from the start of today.
+fn busy_range(feed: &FeedConfig, window: Option<TimeWindow>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let from = window.map(|w| w.start).unwrap_or_else(|| start_of_day(Utc::now(), feed.timezone()));
+    let to = window
+        .and_then(|w| w.end)
+        .unwrap_or_else(|| from + chrono::Duration::days(feed.busy.expand_days.into()));
+    (from, to)
 }
 
-fn parse_weekday(day_str: &str) -> Option<Weekday> {
-    match day_str {
-        "MO" => Some(Weekday::Mon),
-        "TU" => Some(Weekday::Tue),
-        "WE" => Some(Weekday::Wed),
-        "TH" => Some(Weekday::Thu),
-        "FR" => Some(Weekday::Fri),
-        "SA" => Some(Weekday::Sat),
-        "SU" => Some(Weekday::Sun),
-        _ => None,
+/// The periods within `[from, to)` in which a feed offers free slots: the hours of
+/// its availability if it has one, otherwise the working hours of `[feeds.free]`.
+pub fn feed_working_periods(feed: &FeedConfig, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let tz = feed.timezone();
+    match &feed.availability {
+        Some(availability) => working_periods(availability, tz, from, to),
+        None => free::working_periods(from, to, tz, &feed.free),
     }
 }
 
-fn expand_recurring_event(
-    start_dt: NaiveDateTime,
-    end_dt: NaiveDateTime,
-    rrule: &str,
-    window_start: NaiveDateTime,
-    window_end: NaiveDateTime,
-) -> Vec<EventTimeSlot> {
-    let mut occurrences = Vec::new();
-
-    // Parse RRULE - simple implementation for WEEKLY events
-    if !rrule.contains("FREQ=WEEKLY") {
-        // For non-weekly events, just return the original if it's in the window
-        if start_dt >= window_start && start_dt <= window_end {
-            occurrences.push(EventTimeSlot {
-                start: start_dt,
-                end: end_dt,
-                uid: format!("expanded-{}", Uuid::new_v4()),
-            });
-        }
-        return occurrences;
+/// Busy blocks of `components` within `[from, to)`, combined with the time
+/// outside the feed's working hours if it has an availability.
+pub fn feed_busy_slots(
+    components: &[CalendarComponent],
+    holidays: &[CalendarComponent],
+    feed: &FeedConfig,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<BusySlot> {
+    let tz = feed.timezone();
+    let busy = busy_slots(components, &feed.busy, tz, from, to);
+
+    match &feed.availability {
+        Some(availability) => with_unavailable(busy, unavailable_slots(availability, holidays, tz, from, to)),
+        None => busy,
     }
+}
 
-    // Parse BYDAY parameter
-    let weekdays: Vec<Weekday> = if let Some(byday_part) = rrule.split(';').find(|part| part.starts_with("BYDAY=")) {
-        byday_part
-            .strip_prefix("BYDAY=")
-            .unwrap_or("")
-            .split(',')
-            .filter_map(parse_weekday)
-            .collect()
-    } else {
-        // If no BYDAY specified, use the original event's weekday
-        vec![start_dt.weekday()]
-    };
+/// Replaces the default PRODID of icalendar by ours.
+pub fn set_prodid(calendar: &mut Calendar) {
+    calendar.properties.retain(|property| property.key() != "PRODID");
+    calendar.append_property(("PRODID", PRODID));
+}
 
-    // Generate occurrences for each weekday in the window
-    for target_weekday in weekdays {
-        let mut current_date = window_start.date();
+/// Sets the calendar level properties, so subscribing clients show a proper name
+/// and poll as often as the feed is actually refreshed.
+pub fn apply_metadata(mut calendar: Calendar, metadata: &CalendarMetadata, refresh_interval: chrono::Duration) -> Calendar {
+    set_prodid(&mut calendar);
 
-        // Find the first occurrence of target_weekday on or after window_start
-        while current_date.weekday() != target_weekday {
-            current_date = current_date.succ_opt().unwrap_or(current_date);
-            if current_date > window_end.date() {
-                break;
-            }
-        }
+    if let Some(name) = &metadata.name {
+        calendar.name(name);
+    }
 
-        // Generate weekly occurrences
-        while current_date <= window_end.date() {
-            let occurrence_start = current_date.and_time(start_dt.time());
-            let occurrence_end = current_date.and_time(end_dt.time());
+    if let Some(description) = &metadata.description {
+        calendar.description(description);
+    }
 
-            if occurrence_start >= window_start && occurrence_start <= window_end {
-                occurrences.push(EventTimeSlot {
-                    start: occurrence_start,
-                    end: occurrence_end,
-                    uid: format!("expanded-{}", Uuid::new_v4()),
-                });
-            }
+    if let Some(timezone) = &metadata.timezone {
+        calendar.timezone(timezone);
+    }
 
-            // Move to next week
-            current_date += chrono::Duration::weeks(1);
-        }
+    if let Some(color) = &metadata.color {
+        calendar.append_property(("COLOR", color.as_str()));
+    }
+
+    if let Some(source) = &metadata.source {
+        calendar.append_property(Property::new("SOURCE", source).add_parameter("VALUE", "URI").done());
     }
 
-    occurrences
+    calendar.ttl(&refresh_interval);
+    calendar
 }
 
-fn merge_overlapping_events(events: Vec<EventTimeSlot>) -> Vec<EventTimeSlot> {
-    if events.is_empty() {
-        return events;
-    }
+pub async fn calendars_to_merged_calendar(calendars: Vec<Calendar>) -> Calendar {
+    calendars
+        .into_iter()
+        .flat_map(|c| c.components)
+        .collect::<Calendar>()
+}
+
+/// Replaces all events by the busy blocks computed by [`feed_busy_slots`].
+pub fn hide_details(calendar: Calendar, slots: Vec<BusySlot>, feed: &FeedConfig) -> Calendar {
+    // Keep non-event components (VTIMEZONE, etc.)
+    let mut calendar_components: Vec<CalendarComponent> = calendar
+        .components
+        .into_iter()
+        .filter(|component| component.as_event().is_none())
+        .collect();
+
+    for slot in slots {
+        let mut new_event = Event::new();
 
-    // Sort events by start time
-    let mut sorted_events = events;
-    sorted_events.sort_by_key(|e| e.start);
-
-    let mut merged = Vec::new();
-    let mut current = sorted_events[0].clone();
-
-    for event in sorted_events.into_iter().skip(1) {
-        // Check for overlap: current event hasn't ended when next event starts
-        if current.end >= event.start {
-            // Overlap detected - merge them
-            // Take the earliest start and latest end
-            current.start = current.start.min(event.start);
-            current.end = current.end.max(event.end);
-            // Keep the first UID for the merged event
+        new_event.uid(&slot.uid);
+        new_event.starts(slot.start);
+        new_event.ends(slot.end);
+        if let (true, Some(availability)) = (slot.unavailable, &feed.availability) {
+            new_event.summary(&availability.summary);
+        } else if feed.busy.summary_from_source && !slot.labels.is_empty() {
+            new_event.summary(&slot.labels.join(", "));
         } else {
-            // No overlap - save current and move to next
-            merged.push(current);
-            current = event;
+            new_event.summary("Blocked");
         }
+        new_event.status(icalendar::EventStatus::Confirmed);
+
+        calendar_components.push(CalendarComponent::Event(new_event.done()));
     }
 
-    // Don't forget the last event
-    merged.push(current);
-    merged
+    calendar_components.into_iter().collect::<Calendar>()
 }
 
-pub fn filter_future_days(calendar: Calendar, days_limit: u32) -> Calendar {
-    let hide_details_mode = std::env::var("HIDE_DETAILS").unwrap_or_default().to_lowercase() == "true";
-    let today = Local::now().date_naive();
-    let end_date = today + chrono::Duration::days(days_limit as i64);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_load_calendar_keeps_calendar_properties() {
+        let path = std::env::temp_dir().join(format!("ical-merger-load-{}.ics", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//Example//EN\r\nX-WR-CALNAME:Family\r\n\
+             BEGIN:VEVENT\r\nUID:a\r\nDTSTART:20240318T080000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+        )
+        .unwrap();
+        let fetcher = Fetcher::new(&Config::from_vars(&[("URLS", "https://example.com/a.ics")]).unwrap()).unwrap();
+
+        for lenient in [false, true] {
+            let calendar = load_calendar(&fetcher, &path.display().to_string(), lenient).await.unwrap();
+            assert_eq!(calendar.get_name(), Some("Family"));
+            assert_eq!(calendar.components.len(), 1);
+        }
 
-    calendar
-        .components
-        .into_iter()
-        .filter_map(|component| {
-            match component.as_event() {
-                Some(event) => {
-                    // Check if event has a start date
-                    if let Some(start) = event.get_start() {
-                        let event_date = match start {
-                            icalendar::DatePerhapsTime::DateTime(calendar_dt) => {
-                                match calendar_dt {
-                                    icalendar::CalendarDateTime::Floating(naive_dt) => naive_dt.date(),
-                                    icalendar::CalendarDateTime::Utc(utc_dt) => utc_dt.naive_local().date(),
-                                    icalendar::CalendarDateTime::WithTimezone { date_time, .. } => date_time.date(),
-                                }
-                            },
-                            icalendar::DatePerhapsTime::Date(date) => date,
-                        };
-
-                        // Check if this is a recurring event
-                        if let Some(rrule) = event.property_value("RRULE") {
-                            // For recurring events, only include if:
-                            // 1. They start within our future window, OR
-                            // 2. They started recently enough to have current/future occurrences
-                            let max_past_days = if hide_details_mode {
-                                // In privacy mode, be more restrictive - only look back 1 day
-                                // to avoid "mysterious" events from distant past
-                                1
-                            } else {
-                                // Normal mode - look back 90 days for recurring events
-                                90
-                            };
-                            if event_date >= today - chrono::Duration::days(max_past_days) && event_date <= end_date {
-                                // Create a new event with truncated RRULE
-                                let mut new_event = Event::new();
-
-                                // Copy essential properties
-                                if let Some(uid) = event.get_uid() {
-                                    new_event.uid(uid);
-                                } else {
-                                    new_event.uid(&format!("generated-uid-{}", Uuid::new_v4()));
-                                }
-
-                                if let Some(start) = event.get_start() {
-                                    new_event.starts(start);
-                                }
-
-                                if let Some(end) = event.get_end() {
-                                    new_event.ends(end);
-                                }
-
-                                if let Some(summary) = event.get_summary() {
-                                    new_event.summary(summary);
-                                }
-
-                                if let Some(description) = event.get_description() {
-                                    new_event.description(description);
-                                }
-
-                                if let Some(location) = event.get_location() {
-                                    new_event.location(location);
-                                }
-
-                                if let Some(status) = event.get_status() {
-                                    new_event.status(status);
-                                }
-
-                                if let Some(created) = event.get_timestamp() {
-                                    new_event.timestamp(created);
-                                }
-
-                                // Copy other properties that might exist
-                                for prop_name in ["CLASS", "PRIORITY", "SEQUENCE", "TRANSP", "CATEGORIES", "RELATED-TO", "RECURRENCE-ID", "EXDATE"] {
-                                    if let Some(prop_value) = event.property_value(prop_name) {
-                                        new_event.add_property(prop_name, prop_value);
-                                    }
-                                }
-
-                                // Create a truncated RRULE that ends at our date limit
-                                let end_datetime = end_date.and_hms_opt(23, 59, 59).unwrap_or_else(|| {
-                                    end_date.and_hms_opt(0, 0, 0).unwrap()
-                                });
-                                let end_datetime_str = end_datetime.format("%Y%m%dT%H%M%SZ").to_string();
-
-                                // Parse existing RRULE and modify it
-                                let truncated_rrule = if rrule.contains("UNTIL=") {
-                                    // Replace existing UNTIL with our limit
-                                    let parts: Vec<&str> = rrule.split(';').collect();
-                                    let new_parts: Vec<String> = parts.iter().map(|part| {
-                                        if part.starts_with("UNTIL=") {
-                                            format!("UNTIL={}", end_datetime_str)
-                                        } else {
-                                            part.to_string()
-                                        }
-                                    }).collect();
-                                    new_parts.join(";")
-                                } else {
-                                    // Add UNTIL to existing RRULE
-                                    format!("{};UNTIL={}", rrule, end_datetime_str)
-                                };
-
-                                new_event.add_property("RRULE", &truncated_rrule);
-                                Some(CalendarComponent::Event(new_event.done()))
-                            } else {
-                                // Recurring event starts after our window, exclude it
-                                None
-                            }
-                        } else {
-                            // Non-recurring event: include only if in window
-                            if event_date >= today && event_date <= end_date {
-                                Some(component)
-                            } else {
-                                None
-                            }
-                        }
-                    } else {
-                        // Keep events without start date (shouldn't happen, but be safe)
-                        Some(component)
-                    }
-                },
-                None => {
-                    // Keep non-event components (VTIMEZONE, etc.)
-                    Some(component)
-                }
-            }
-        })
-        .collect::<Calendar>()
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tags_events_with_their_source() {
+        let source: SourceConfig = toml::from_str(
+            r##"
+            url = "https://example.com/family.ics"
+            summary_prefix = "[Family]"
+            categories = ["Family", "Private"]
+            color = "#ff0000"
+            "##,
+        )
+        .unwrap();
+        let untitled: SourceConfig = toml::from_str(r#"url = "https://example.com/b.ics""#).unwrap();
+
+        let tagged = tag_with_source(Event::new().summary("Dinner").done().into(), &source);
+        let event = tagged.as_event().unwrap();
+        assert_eq!(event.get_summary(), Some("[Family] Dinner"));
+        assert_eq!(event.property_value("COLOR"), Some("#ff0000"));
+        assert_eq!(event.property_value(SOURCE_PROPERTY), Some("example.com"));
+        let categories: Vec<&str> = event.multi_properties()["CATEGORIES"].iter().map(Property::value).collect();
+        assert_eq!(categories, vec!["Family", "Private"]);
+
+        // Without a summary the prefix becomes the summary.
+        let tagged = tag_with_source(Event::new().done().into(), &source);
+        assert_eq!(tagged.as_event().unwrap().get_summary(), Some("[Family]"));
+
+        let tagged = tag_with_source(Event::new().summary("Dinner").done().into(), &untitled);
+        let event = tagged.as_event().unwrap();
+        assert_eq!(event.get_summary(), Some("Dinner"));
+        assert_eq!(event.property_value("COLOR"), None);
+        assert!(!event.multi_properties().contains_key("CATEGORIES"));
+    }
 }
@@ -1,7 +1,22 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
 use serde::Deserialize;
 
+use crate::lib::alarms::AlarmPolicy;
+use crate::lib::availability::Availability;
+use crate::lib::busy::BusyOptions;
+use crate::lib::dedup::{DedupOptions, DedupStrategy};
+use crate::lib::error::{Error, Result};
+use crate::lib::filter::FilterRule;
+use crate::lib::free::FreeOptions;
+use crate::lib::logging::LogFormat;
+use crate::lib::rewrite::RewriteRule;
+use crate::lib::window::TimeWindow;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub urls: Vec<String>,
 
     #[serde(default = "default_tz_offsets")]
@@ -16,8 +31,476 @@ pub struct Config {
     #[serde(default = "default_hide_details")]
     pub hide_details: bool,
 
-    #[serde(default = "default_future_days_limit")]
-    pub future_days_limit: Option<u32>,
+    #[serde(default)]
+    pub past_days: Option<u32>,
+
+    #[serde(default, alias = "future_days_limit")]
+    pub future_days: Option<u32>,
+
+    #[serde(default)]
+    pub busy_summary_from_source: bool,
+
+    #[serde(default)]
+    pub busy_exclude_all_day: bool,
+
+    #[serde(default = "default_lenient_parsing")]
+    pub lenient_parsing: bool,
+
+    #[serde(default = "default_deduplicate")]
+    pub deduplicate: bool,
+
+    #[serde(default)]
+    pub dedup_fuzzy_tolerance_secs: Option<u32>,
+
+    #[serde(default)]
+    pub dedup_strategy: DedupStrategy,
+
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+
+    #[serde(default = "default_http_timeout_secs")]
+    pub http_timeout_secs: u64,
+
+    #[serde(default = "default_http_max_response_bytes")]
+    pub http_max_response_bytes: u64,
+
+    #[serde(default = "default_http_max_redirects")]
+    pub http_max_redirects: usize,
+
+    #[serde(default = "default_http_max_connections_per_host")]
+    pub http_max_connections_per_host: usize,
+
+    #[serde(default)]
+    pub proxy: Option<String>,
+
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+
+    #[serde(default = "default_circuit_breaker_threshold")]
+    pub circuit_breaker_threshold: u32,
+
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    pub circuit_breaker_cooldown_secs: u64,
+
+    #[serde(default = "default_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+
+    #[serde(default)]
+    pub calendar_name: Option<String>,
+
+    #[serde(default)]
+    pub calendar_description: Option<String>,
+
+    #[serde(default)]
+    pub calendar_color: Option<String>,
+
+    #[serde(default)]
+    pub calendar_timezone: Option<String>,
+
+    #[serde(default)]
+    pub calendar_source: Option<String>,
+
+    /// TOML file defining the served feeds. Without it, a single feed is built
+    /// from `URLS`, `TZ_OFFSETS` and the other environment variables.
+    #[serde(default)]
+    pub config_file: Option<PathBuf>,
+
+    /// How often the server checks `config_file` for changes, 0 to only
+    /// reload on SIGHUP.
+    #[serde(default = "default_config_reload_interval_secs")]
+    pub config_reload_interval_secs: u64,
+
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    /// Minimum level or `tracing` filter directives, e.g. `info,ical_merger=debug`.
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+
+    /// Serve Prometheus metrics at `/metrics`.
+    #[serde(default)]
+    pub metrics_enabled: bool,
+
+    /// Serve the metrics on this `host:port` instead of next to the feeds.
+    #[serde(default)]
+    pub metrics_address: Option<String>,
+
+    #[serde(skip)]
+    pub feeds: Vec<FeedConfig>,
+}
+
+/// A merged calendar that is served under its own name.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FeedConfig {
+    pub name: String,
+
+    pub sources: Vec<SourceConfig>,
+
+    /// Every source is one person, which enables `/feeds/<name>/group`. The
+    /// sources then need distinct names to tell the people apart.
+    #[serde(default)]
+    pub group: bool,
+
+    #[serde(default = "default_hide_details")]
+    pub hide_details: bool,
+
+    #[serde(default)]
+    pub busy: BusyOptions,
+
+    /// Working hours; the time outside of them is shown as busy.
+    #[serde(default)]
+    pub availability: Option<Availability>,
+
+    /// Working hours and slot sizes of `/feeds/<name>/free`.
+    #[serde(default)]
+    pub free: FreeOptions,
+
+    /// Keep events that ended at most this many days ago. Without it, only
+    /// ongoing and upcoming events are kept once `future_days` is set.
+    #[serde(default)]
+    pub past_days: Option<u32>,
+
+    /// Drop events starting more than this many days from now.
+    #[serde(default, alias = "future_days_limit")]
+    pub future_days: Option<u32>,
+
+    /// Applied to the merged events, before details are hidden.
+    #[serde(default)]
+    pub filters: Vec<FilterRule>,
+
+    #[serde(default)]
+    pub alarms: AlarmPolicy,
+
+    #[serde(default)]
+    pub calendar: CalendarMetadata,
+
+    /// The table of the config file this feed was read from, to tell whether a
+    /// reload changed it.
+    #[serde(skip)]
+    pub definition: Option<toml::Value>,
+}
+
+impl FeedConfig {
+    /// Whether both feeds were read from the same table of a config file.
+    pub fn same_definition(&self, other: &FeedConfig) -> bool {
+        self.definition.is_some() && self.definition == other.definition
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    pub url: String,
+
+    #[serde(default)]
+    pub tz_offset: i64,
+
+    /// Label written to `X-ICAL-MERGER-SOURCE`, defaults to the host of the url.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Prepended to the summary of every event, e.g. `[Family]`.
+    #[serde(default)]
+    pub summary_prefix: Option<String>,
+
+    /// Appended to the CATEGORIES of every event.
+    #[serde(default)]
+    pub categories: Vec<String>,
+
+    /// RFC 7986 COLOR set on every event.
+    #[serde(default)]
+    pub color: Option<String>,
+
+    #[serde(default)]
+    pub filters: Vec<FilterRule>,
+
+    /// Applied in order to the events left after `filters`.
+    #[serde(default)]
+    pub rewrites: Vec<RewriteRule>,
+}
+
+impl FeedConfig {
+    /// Timezone used for floating times and day boundaries, from the calendar
+    /// metadata and UTC otherwise.
+    pub fn timezone(&self) -> chrono_tz::Tz {
+        self.calendar
+            .timezone
+            .as_deref()
+            .and_then(|tz| tz.parse().ok())
+            .unwrap_or(chrono_tz::Tz::UTC)
+    }
+
+    /// The span of time published at `now`, from `past_days` and `future_days`.
+    pub fn window(&self, now: chrono::DateTime<chrono::Utc>) -> Option<TimeWindow> {
+        TimeWindow::around(now, self.timezone(), self.past_days, self.future_days)
+    }
+}
+
+impl SourceConfig {
+    /// Name identifying this source in the merged feed, logs, status and errors.
+    /// The url itself is never shown, as calendar urls often carry a private
+    /// token in their path or query.
+    pub fn label(&self) -> String {
+        self.name.clone().unwrap_or_else(|| host_label(&self.url))
+    }
+}
+
+/// The host of `url`, which names a source without a `name`, see [`SourceConfig::label`].
+pub fn host_label(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Calendar level properties of a merged feed (RFC 7986 and the common `X-WR-` ones).
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CalendarMetadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub color: Option<String>,
+    pub timezone: Option<String>,
+    /// Where subscribers can fetch this feed from.
+    pub source: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    /// Read one by one, so that a feed that cannot be read does not hide the
+    /// problems of the others.
+    feeds: Vec<toml::Value>,
+}
+
+/// Reads every feed that can be read and adds a problem for each other one.
+fn read_feeds(values: Vec<toml::Value>, problems: &mut Vec<String>) -> Vec<FeedConfig> {
+    values
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, value)| {
+            let context = match value.get("name").and_then(toml::Value::as_str) {
+                Some(name) => format!("feed {name:?}"),
+                None => format!("feed {}", index + 1),
+            };
+            let mut feed = value
+                .clone()
+                .try_into::<FeedConfig>()
+                .inspect_err(|e| problems.push(format!("{context}: {}", e.message().trim())))
+                .ok()?;
+            feed.definition = Some(value);
+            Some(feed)
+        })
+        .collect()
+}
+
+impl Config {
+    /// Reads the configuration from the environment and, if set, the `CONFIG_FILE`.
+    pub fn load() -> Result<Self> {
+        Self::from_env()?.with_feeds()
+    }
+
+    /// Reads the environment only, without building any feeds.
+    pub fn from_env() -> Result<Self> {
+        Ok(envy::from_env::<Config>()?)
+    }
+
+    /// Builds the feeds from `config_file` or, without one, from `urls`.
+    pub fn with_feeds(mut self) -> Result<Self> {
+        let mut problems = Vec::new();
+        self.feeds = match &self.config_file {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| Error::Config(format!("cannot read {}: {e}", path.display())))?;
+                let file = toml::from_str::<ConfigFile>(&text)
+                    .map_err(|e| Error::Config(format!("cannot parse {}: {e}", path.display())))?;
+                read_feeds(file.feeds, &mut problems)
+            }
+            None if self.urls.is_empty() => {
+                return Err(Error::Config("either URLS or CONFIG_FILE has to be set".into()));
+            }
+            None => vec![self.default_feed()],
+        };
+
+        if self.feeds.is_empty() && problems.is_empty() {
+            return Err(Error::Config("the config file does not define any feeds".into()));
+        }
+
+        problems.extend(self.problems());
+        if problems.is_empty() {
+            Ok(self)
+        } else {
+            Err(Error::InvalidConfig(problems))
+        }
+    }
+
+    /// Checks what deserializing alone does not and reports all problems at once.
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidConfig(problems))
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !(1..=65535).contains(&self.port) {
+            problems.push(format!("PORT {} is not between 1 and 65535", self.port));
+        }
+        if let Some(proxy) = &self.proxy {
+            check_url(&mut problems, "PROXY", proxy, &["http", "https", "socks5", "socks5h"]);
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_level) {
+            problems.push(format!("LOG_LEVEL {:?} is not a valid filter: {e}", self.log_level));
+        }
+        if let Some(address) = &self.metrics_address {
+            if address.parse::<std::net::SocketAddr>().is_err() {
+                problems.push(format!("METRICS_ADDRESS {address:?} is not a host:port like 0.0.0.0:9100"));
+            }
+        }
+
+        let mut names = HashSet::new();
+        for feed in &self.feeds {
+            let context = format!("feed {:?}", feed.name);
+
+            if feed.name.is_empty() || feed.name.contains('/') {
+                problems.push(format!("{context}: names must not be empty or contain `/`"));
+            }
+            if !names.insert(feed.name.as_str()) {
+                problems.push(format!("{context}: defined more than once"));
+            }
+            if feed.sources.is_empty() {
+                problems.push(format!("{context}: has no sources"));
+            }
+
+            check_timezone(&mut problems, &format!("{context}: calendar.timezone"), feed.calendar.timezone.as_deref());
+            if let Some(availability) = &feed.availability {
+                check_timezone(&mut problems, &format!("{context}: availability.timezone"), availability.timezone.as_deref());
+                if let Some(url) = &availability.holiday_source {
+                    check_url(&mut problems, &format!("{context}: availability.holiday_source"), url, SOURCE_SCHEMES);
+                }
+            }
+
+            let mut people = HashSet::new();
+            for (index, source) in feed.sources.iter().enumerate() {
+                let context = format!("{context}: source {}", index + 1);
+                check_url(&mut problems, &context, &source.url, SOURCE_SCHEMES);
+                if feed.group {
+                    match &source.name {
+                        None => problems.push(format!("{context}: needs a name since the feed is a group")),
+                        Some(name) if !people.insert(name.as_str()) => {
+                            problems.push(format!("{context}: name {name:?} is used by another source of the group"))
+                        }
+                        Some(_) => {}
+                    }
+                }
+                if !(-24..=24).contains(&source.tz_offset) {
+                    problems.push(format!("{context}: tz_offset {} is not between -24 and 24 hours", source.tz_offset));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// The feed described by the plain environment variables.
+    fn default_feed(&self) -> FeedConfig {
+        let sources = self
+            .urls
+            .iter()
+            .enumerate()
+            .map(|(index, url)| SourceConfig {
+                url: url.clone(),
+                // Sources beyond the list of offsets use the last one.
+                tz_offset: self
+                    .tz_offsets
+                    .get(index)
+                    .or(self.tz_offsets.last())
+                    .copied()
+                    .unwrap_or_default(),
+                name: None,
+                summary_prefix: None,
+                categories: Vec::new(),
+                color: None,
+                filters: Vec::new(),
+                rewrites: Vec::new(),
+            })
+            .collect();
+
+        FeedConfig {
+            name: "default".into(),
+            sources,
+            group: false,
+            hide_details: self.hide_details,
+            busy: BusyOptions {
+                summary_from_source: self.busy_summary_from_source,
+                exclude_all_day: self.busy_exclude_all_day,
+                ..Default::default()
+            },
+            past_days: self.past_days,
+            future_days: self.future_days,
+            filters: Vec::new(),
+            alarms: AlarmPolicy::default(),
+            availability: None,
+            free: FreeOptions::default(),
+            calendar: CalendarMetadata {
+                name: self.calendar_name.clone(),
+                description: self.calendar_description.clone(),
+                color: self.calendar_color.clone(),
+                timezone: self.calendar_timezone.clone(),
+                source: self.calendar_source.clone(),
+            },
+            definition: None,
+        }
+    }
+
+    pub fn feed(&self, name: &str) -> Option<&FeedConfig> {
+        self.feeds.iter().find(|feed| feed.name == name)
+    }
+
+    pub fn dedup_options(&self) -> DedupOptions {
+        DedupOptions {
+            enabled: self.deduplicate,
+            fuzzy_tolerance: self
+                .dedup_fuzzy_tolerance_secs
+                .map(|secs| chrono::Duration::seconds(secs.into())),
+            strategy: self.dedup_strategy,
+        }
+    }
+}
+
+/// Calendars can only be fetched over http(s).
+const SOURCE_SCHEMES: &[&str] = &["http", "https"];
+
+fn check_url(problems: &mut Vec<String>, context: &str, url: &str, schemes: &[&str]) {
+    match reqwest::Url::parse(url) {
+        // The url itself is left out, see `SourceConfig::label`.
+        Err(e) => problems.push(format!("{context}: invalid url: {e}")),
+        Ok(parsed) if !schemes.contains(&parsed.scheme()) => problems.push(format!(
+            "{context}: unsupported scheme `{}`, expected one of {}",
+            parsed.scheme(),
+            schemes.join(", ")
+        )),
+        Ok(_) => {}
+    }
+}
+
+fn check_timezone(problems: &mut Vec<String>, context: &str, timezone: Option<&str>) {
+    if let Some(timezone) = timezone {
+        if timezone.parse::<chrono_tz::Tz>().is_err() {
+            problems.push(format!("{context}: unknown IANA timezone {timezone:?}"));
+        }
+    }
 }
 
 fn default_port() -> u32 {
@@ -36,6 +519,196 @@ fn default_tz_offsets() -> Vec<i64> {
     Vec::new()
 }
 
-fn default_future_days_limit() -> Option<u32> {
-    None
+fn default_lenient_parsing() -> bool {
+    true
+}
+
+fn default_deduplicate() -> bool {
+    true
+}
+
+fn default_refresh_interval_secs() -> u64 {
+    900
+}
+
+fn default_log_level() -> String {
+    "info".into()
+}
+
+fn default_config_reload_interval_secs() -> u64 {
+    5
+}
+
+fn default_user_agent() -> String {
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36".into()
+}
+
+fn default_http_timeout_secs() -> u64 {
+    30
+}
+
+fn default_http_max_response_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_http_max_redirects() -> usize {
+    10
+}
+
+fn default_http_max_connections_per_host() -> usize {
+    4
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    10_000
+}
+
+fn default_circuit_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    300
+}
+
+#[cfg(test)]
+impl Config {
+    /// Reads `vars` as if they were the environment and builds the feeds.
+    pub fn from_vars(vars: &[(&str, &str)]) -> Result<Self> {
+        let vars = vars.iter().map(|(key, value)| (key.to_string(), value.to_string()));
+        envy::from_iter::<_, Config>(vars)?.with_feeds()
+    }
+}
+
+/// Writes `contents` to a new file in the temp directory, to be used as `CONFIG_FILE`.
+#[cfg(test)]
+pub fn temp_config_file(contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ical-merger-config-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(file: &str) -> Result<Config> {
+        let path = temp_config_file(file);
+        let config = Config::from_vars(&[("CONFIG_FILE", &path.display().to_string())]);
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_reports_all_problems_at_once() {
+        let err = config(
+            r#"
+            [[feeds]]
+            name = "team"
+            calendar = { timezone = "Europe/Berlinn" }
+            sources = [{ url = "webcal://example.com/a.ics" }, { url = "not a url", tz_offset = 30 }]
+
+            [[feeds]]
+            name = "team"
+            sources = []
+            "#,
+        )
+        .unwrap_err();
+
+        let Error::InvalidConfig(problems) = err else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!(
+            problems,
+            vec![
+                r#"feed "team": calendar.timezone: unknown IANA timezone "Europe/Berlinn""#,
+                r#"feed "team": source 1: unsupported scheme `webcal`, expected one of http, https"#,
+                r#"feed "team": source 2: invalid url: relative URL without a base"#,
+                r#"feed "team": source 2: tz_offset 30 is not between -24 and 24 hours"#,
+                r#"feed "team": defined more than once"#,
+                r#"feed "team": has no sources"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_keys() {
+        let feed = |extra: &str| format!("[[feeds]]\nname = \"a\"\nsources = [{{ url = \"https://example.com\" }}]\n{extra}");
+
+        assert!(config(&feed("")).is_ok());
+        assert!(config(&feed("hide_detials = true")).is_err());
+        assert!(config(&feed("[[feeds.filters]]\naction = \"exclude\"\nsumary = \"x\"")).is_err());
+        assert!(config(&feed("[feeds.busy]\npadding_mins = 5")).is_err());
+    }
+
+    #[test]
+    fn test_group_sources_need_distinct_names() {
+        let err = config(
+            r#"
+            [[feeds]]
+            name = "team"
+            group = true
+            sources = [{ url = "https://calendar.google.com/a.ics" }, { url = "https://calendar.google.com/b.ics" }]
+
+            [[feeds]]
+            name = "pair"
+            group = true
+            sources = [
+                { url = "https://calendar.google.com/a.ics", name = "Alice" },
+                { url = "https://calendar.google.com/b.ics", name = "Alice" },
+            ]
+            "#,
+        )
+        .unwrap_err();
+
+        let Error::InvalidConfig(problems) = err else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!(
+            problems,
+            vec![
+                r#"feed "team": source 1: needs a name since the feed is a group"#,
+                r#"feed "team": source 2: needs a name since the feed is a group"#,
+                r#"feed "pair": source 2: name "Alice" is used by another source of the group"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reports_unreadable_feeds_together() {
+        let err = config(
+            r#"
+            [[feeds]]
+            name = "team"
+            sources = [{ url = "https://example.com/a.ics" }]
+            filters = [{ action = "exclude", summary = "(unclosed" }]
+
+            [[feeds]]
+            name = "family"
+            hide_detials = true
+            sources = [{ url = "https://example.com/b.ics" }]
+
+            [[feeds]]
+            name = "home"
+            sources = [{ url = "ftp://example.com/c.ics" }]
+            "#,
+        )
+        .unwrap_err();
+
+        let Error::InvalidConfig(problems) = err else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems[0].starts_with(r#"feed "team": "#) && problems[0].contains("unclosed group"));
+        assert!(problems[1].starts_with(r#"feed "family": unknown field `hide_detials`"#));
+        assert!(problems[2].starts_with(r#"feed "home": source 1: unsupported scheme `ftp`"#));
+    }
 }
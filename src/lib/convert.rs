@@ -0,0 +1,107 @@
+use chrono_tz::Tz;
+use icalendar::{CalendarComponent, Component, EventLike};
+use serde::Serialize;
+
+use crate::lib::calendar::SOURCE_PROPERTY;
+use crate::lib::recurrence::occurrences;
+
+/// An event as written by `cli convert --format json`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct EventJson {
+    pub uid: Option<String>,
+    pub summary: Option<String>,
+    /// RFC 3339 in the target timezone, or a date for all-day events.
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub all_day: bool,
+    pub location: Option<String>,
+    pub description: Option<String>,
+    pub status: Option<String>,
+    /// The RRULE of recurring events, which are not expanded.
+    pub rrule: Option<String>,
+    pub source: Option<String>,
+}
+
+/// One entry per event. Floating times and dates are read in `tz`.
+pub fn events_to_json(components: &[CalendarComponent], tz: Tz) -> Vec<EventJson> {
+    components
+        .iter()
+        .filter_map(CalendarComponent::as_event)
+        .map(|event| {
+            let first = occurrences(event, tz).next();
+            let format = |time: chrono::DateTime<chrono::Utc>, all_day: bool| {
+                let local = time.with_timezone(&tz);
+                if all_day {
+                    local.format("%Y-%m-%d").to_string()
+                } else {
+                    local.to_rfc3339()
+                }
+            };
+            let text = |key: &str| event.property_value(key).map(str::to_string);
+
+            EventJson {
+                uid: text("UID"),
+                summary: text("SUMMARY"),
+                start: first.as_ref().map(|occurrence| format(occurrence.start, occurrence.all_day)),
+                end: first.as_ref().map(|occurrence| format(occurrence.end, occurrence.all_day)),
+                all_day: first.as_ref().is_some_and(|occurrence| occurrence.all_day),
+                location: event.get_location().map(str::to_string),
+                description: event.get_description().map(str::to_string),
+                status: text("STATUS"),
+                rrule: text("RRULE"),
+                source: text(SOURCE_PROPERTY),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use icalendar::Calendar;
+
+    #[test]
+    fn test_events_to_json() {
+        let text = "BEGIN:VCALENDAR\r\n\
+                    BEGIN:VEVENT\r\nUID:standup\r\nSUMMARY:Standup\r\nDTSTART:20240318T080000Z\r\n\
+                    DTEND:20240318T081500Z\r\nRRULE:FREQ=DAILY\r\nLOCATION:Office\r\n\
+                    X-ICAL-MERGER-SOURCE:Work\r\nEND:VEVENT\r\n\
+                    BEGIN:VEVENT\r\nUID:trip\r\nDTSTART;VALUE=DATE:20240320\r\nDTEND;VALUE=DATE:20240322\r\n\
+                    STATUS:TENTATIVE\r\nEND:VEVENT\r\n\
+                    BEGIN:VTODO\r\nUID:todo\r\nEND:VTODO\r\n\
+                    END:VCALENDAR\r\n";
+        let components = text.parse::<Calendar>().unwrap().components;
+
+        let events = events_to_json(&components, chrono_tz::Europe::Berlin);
+
+        assert_eq!(
+            events,
+            vec![
+                EventJson {
+                    uid: Some("standup".into()),
+                    summary: Some("Standup".into()),
+                    start: Some("2024-03-18T09:00:00+01:00".into()),
+                    end: Some("2024-03-18T09:15:00+01:00".into()),
+                    all_day: false,
+                    location: Some("Office".into()),
+                    description: None,
+                    status: None,
+                    rrule: Some("FREQ=DAILY".into()),
+                    source: Some("Work".into()),
+                },
+                EventJson {
+                    uid: Some("trip".into()),
+                    summary: None,
+                    start: Some("2024-03-20".into()),
+                    end: Some("2024-03-22".into()),
+                    all_day: true,
+                    location: None,
+                    description: None,
+                    status: Some("TENTATIVE".into()),
+                    rrule: None,
+                    source: None,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use icalendar::{CalendarComponent, Component, Event};
+use serde::Deserialize;
+
+use crate::lib::timezone::resolve_utc;
+
+/// Decides which copy of a duplicated event is kept.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupStrategy {
+    /// The copy with the higher SEQUENCE, then the newer LAST-MODIFIED wins.
+    /// Ties are broken by source priority.
+    #[default]
+    Sequence,
+    /// The copy from the source listed first wins.
+    Priority,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DedupOptions {
+    pub enabled: bool,
+    /// Events from different sources with the same summary whose start and end
+    /// are at most this far apart are treated as duplicates. `None` only matches
+    /// exact duplicates by UID and RECURRENCE-ID.
+    pub fuzzy_tolerance: Option<Duration>,
+    pub strategy: DedupStrategy,
+}
+
+struct Candidate {
+    source: usize,
+    event: Event,
+}
+
+/// Merges the components of all sources, dropping duplicated events.
+///
+/// `sources` is ordered by priority, the first source has the highest one.
+/// Every kept component is returned with the index of the source it came from.
+pub fn deduplicate(sources: Vec<Vec<CalendarComponent>>, options: &DedupOptions) -> Vec<(usize, CalendarComponent)> {
+    if !options.enabled {
+        return sources
+            .into_iter()
+            .enumerate()
+            .flat_map(|(source, components)| components.into_iter().map(move |c| (source, c)))
+            .collect();
+    }
+
+    let mut others = Vec::new();
+    let mut kept: Vec<Option<Candidate>> = Vec::new();
+    let mut by_uid: HashMap<(String, String), usize> = HashMap::new();
+    let mut by_summary: HashMap<(String, String), Vec<usize>> = HashMap::new();
+
+    for (source, components) in sources.into_iter().enumerate() {
+        for component in components {
+            let CalendarComponent::Event(event) = component else {
+                others.push((source, component));
+                continue;
+            };
+
+            let candidate = Candidate { source, event };
+            let uid_key = uid_key(&candidate.event);
+            let summary_key = summary_key(&candidate.event);
+
+            let duplicate = uid_key
+                .as_ref()
+                .and_then(|key| by_uid.get(key).copied())
+                .or_else(|| {
+                    let tolerance = options.fuzzy_tolerance?;
+                    by_summary.get(summary_key.as_ref()?)?.iter().copied().find(|&index| {
+                        kept[index]
+                            .as_ref()
+                            .is_some_and(|existing| is_fuzzy_duplicate(existing, &candidate, tolerance))
+                    })
+                });
+
+            let index = match duplicate {
+                Some(index) => {
+                    let existing = kept[index].take().expect("indexed events are always kept");
+                    kept[index] = Some(pick_winner(existing, candidate, options.strategy));
+                    index
+                }
+                None => {
+                    kept.push(Some(candidate));
+                    kept.len() - 1
+                }
+            };
+
+            if let Some(key) = uid_key {
+                by_uid.entry(key).or_insert(index);
+            }
+            if let Some(key) = summary_key {
+                let indices = by_summary.entry(key).or_default();
+                if !indices.contains(&index) {
+                    indices.push(index);
+                }
+            }
+        }
+    }
+
+    others
+        .into_iter()
+        .chain(kept.into_iter().flatten().map(|c| (c.source, CalendarComponent::Event(c.event))))
+        .collect()
+}
+
+fn uid_key(event: &Event) -> Option<(String, String)> {
+    let uid = event.get_uid()?.to_string();
+    let recurrence_id = event
+        .get_recurrence_id()
+        .and_then(|rid| resolve_utc(&rid, Tz::UTC))
+        .map(|rid| rid.to_rfc3339())
+        .unwrap_or_default();
+
+    Some((uid, recurrence_id))
+}
+
+/// Fuzzy matching only considers events with the same summary and the same RRULE,
+/// so a single event never swallows a whole series.
+fn summary_key(event: &Event) -> Option<(String, String)> {
+    let summary = event.get_summary()?.trim().to_lowercase();
+    let rrule = event.property_value("RRULE").unwrap_or_default().to_string();
+
+    Some((summary, rrule))
+}
+
+fn time_range(event: &Event) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let start = resolve_utc(&event.get_start()?, Tz::UTC)?;
+    let end = event
+        .get_end()
+        .and_then(|end| resolve_utc(&end, Tz::UTC))
+        .unwrap_or(start);
+
+    Some((start, end))
+}
+
+fn is_fuzzy_duplicate(existing: &Candidate, candidate: &Candidate, tolerance: Duration) -> bool {
+    if existing.source == candidate.source {
+        return false;
+    }
+
+    match (time_range(&existing.event), time_range(&candidate.event)) {
+        (Some((start_a, end_a)), Some((start_b, end_b))) => {
+            (start_a - start_b).abs() <= tolerance && (end_a - end_b).abs() <= tolerance
+        }
+        _ => false,
+    }
+}
+
+fn pick_winner(existing: Candidate, candidate: Candidate, strategy: DedupStrategy) -> Candidate {
+    let by_revision = || {
+        candidate
+            .event
+            .get_sequence()
+            .unwrap_or(0)
+            .cmp(&existing.event.get_sequence().unwrap_or(0))
+            .then_with(|| candidate.event.get_last_modified().cmp(&existing.event.get_last_modified()))
+    };
+    // Lower source index means higher priority.
+    let by_priority = || existing.source.cmp(&candidate.source);
+
+    let ordering = match strategy {
+        DedupStrategy::Sequence => by_revision().then_with(by_priority),
+        DedupStrategy::Priority => by_priority().then_with(by_revision),
+    };
+
+    match ordering {
+        Ordering::Greater => candidate,
+        _ => existing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use icalendar::EventLike;
+
+    fn event(uid: &str, summary: &str, hour: u32, minute: u32) -> CalendarComponent {
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+            .and_utc();
+
+        Event::new()
+            .uid(uid)
+            .summary(summary)
+            .starts(start)
+            .ends(start + Duration::hours(1))
+            .done()
+            .into()
+    }
+
+    fn with_sequence(component: CalendarComponent, sequence: u32) -> CalendarComponent {
+        let CalendarComponent::Event(mut event) = component else {
+            unreachable!()
+        };
+        event.sequence(sequence);
+        event.into()
+    }
+
+    fn summaries(components: &[(usize, CalendarComponent)]) -> Vec<&str> {
+        components
+            .iter()
+            .filter_map(|(_, c)| c.as_event()?.get_summary())
+            .collect()
+    }
+
+    #[test]
+    fn test_exact_duplicates_keep_highest_sequence() {
+        let options = DedupOptions {
+            enabled: true,
+            ..Default::default()
+        };
+        let work = vec![event("invite", "Old title", 10, 0)];
+        let personal = vec![with_sequence(event("invite", "New title", 10, 0), 2)];
+
+        let merged = deduplicate(vec![work, personal], &options);
+
+        assert_eq!(summaries(&merged), vec!["New title"]);
+    }
+
+    #[test]
+    fn test_priority_strategy_prefers_first_source() {
+        let options = DedupOptions {
+            enabled: true,
+            fuzzy_tolerance: None,
+            strategy: DedupStrategy::Priority,
+        };
+        let work = vec![event("invite", "Work copy", 10, 0)];
+        let personal = vec![with_sequence(event("invite", "Personal copy", 10, 0), 2)];
+
+        let merged = deduplicate(vec![work, personal], &options);
+
+        assert_eq!(summaries(&merged), vec!["Work copy"]);
+    }
+
+    #[test]
+    fn test_fuzzy_duplicates_respect_tolerance() {
+        let options = DedupOptions {
+            enabled: true,
+            fuzzy_tolerance: Some(Duration::minutes(5)),
+            strategy: DedupStrategy::Sequence,
+        };
+        let work = vec![event("a", "Standup", 10, 0), event("b", "Review", 14, 0)];
+        let personal = vec![event("c", "standup ", 10, 3), event("d", "Review", 14, 30)];
+
+        let merged = deduplicate(vec![work, personal], &options);
+
+        assert_eq!(summaries(&merged), vec!["Standup", "Review", "Review"]);
+    }
+
+    #[test]
+    fn test_disabled_keeps_everything() {
+        let work = vec![event("invite", "Meeting", 10, 0)];
+        let personal = vec![event("invite", "Meeting", 10, 0)];
+
+        let merged = deduplicate(vec![work, personal], &DedupOptions::default());
+
+        assert_eq!(merged.len(), 2);
+    }
+}
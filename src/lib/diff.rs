@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use icalendar::{CalendarComponent, Component, Event, Property};
+
+/// Properties that change on every export and say nothing about the event.
+const IGNORED_PROPERTIES: [&str; 1] = ["DTSTAMP"];
+
+/// A difference between two versions of a calendar, per event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added { key: String, summary: String },
+    Removed { key: String, summary: String },
+    /// `properties` lists the names of the properties that differ.
+    Changed { key: String, summary: String, properties: Vec<String> },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { key, summary } => write!(f, "+ {key} {summary}"),
+            Change::Removed { key, summary } => write!(f, "- {key} {summary}"),
+            Change::Changed { key, summary, properties } => {
+                write!(f, "~ {key} {summary}: {}", properties.join(", "))
+            }
+        }
+    }
+}
+
+/// Compares the events of two calendars. Events are matched by UID and
+/// RECURRENCE-ID, or by start and summary if they have no UID. Events sharing
+/// both are matched in the order they appear.
+pub fn diff_calendars(old: &[CalendarComponent], new: &[CalendarComponent]) -> Vec<Change> {
+    let old = by_key(old);
+    let new = by_key(new);
+    let mut changes = Vec::new();
+
+    for (key, events) in &old {
+        let updates = new.get(key).map(Vec::as_slice).unwrap_or_default();
+        for (index, event) in events.iter().enumerate() {
+            match updates.get(index) {
+                None => changes.push(Change::Removed {
+                    key: key.to_string(),
+                    summary: summary(event),
+                }),
+                Some(updated) => {
+                    let properties = changed_properties(event, updated);
+                    if !properties.is_empty() {
+                        changes.push(Change::Changed {
+                            key: key.to_string(),
+                            summary: summary(updated),
+                            properties,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    for (key, events) in &new {
+        let known = old.get(key).map_or(0, Vec::len);
+        for event in events.iter().skip(known) {
+            changes.push(Change::Added {
+                key: key.to_string(),
+                summary: summary(event),
+            });
+        }
+    }
+
+    changes
+}
+
+/// What an event is matched by: its UID, or start and summary without one,
+/// and the RECURRENCE-ID of an override.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    id: String,
+    recurrence_id: Option<String>,
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.recurrence_id {
+            Some(recurrence_id) => write!(f, "{}@{recurrence_id}", self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+fn by_key(components: &[CalendarComponent]) -> BTreeMap<Key, Vec<&Event>> {
+    let mut events: BTreeMap<Key, Vec<&Event>> = BTreeMap::new();
+
+    for event in components.iter().filter_map(CalendarComponent::as_event) {
+        let id = match event.get_uid() {
+            Some(uid) => uid.to_string(),
+            None => format!(
+                "{}@{}",
+                event.property_value("DTSTART").unwrap_or_default(),
+                event.get_summary().unwrap_or_default()
+            ),
+        };
+        let recurrence_id = event.property_value("RECURRENCE-ID").map(str::to_string);
+        events.entry(Key { id, recurrence_id }).or_default().push(event);
+    }
+    events
+}
+
+fn summary(event: &Event) -> String {
+    event.get_summary().unwrap_or_default().to_string()
+}
+
+/// Names of the properties whose values or parameters differ, including
+/// repeated ones like ATTENDEE, followed by the kinds of subcomponents like
+/// VALARM that were added, removed or changed.
+fn changed_properties(old: &Event, new: &Event) -> Vec<String> {
+    let old_properties = comparable(old);
+    let new_properties = comparable(new);
+
+    let mut names: BTreeSet<&String> = old_properties.keys().collect();
+    names.extend(new_properties.keys());
+
+    let mut changed: Vec<String> = names
+        .into_iter()
+        .filter(|name| old_properties.get(*name) != new_properties.get(*name))
+        .cloned()
+        .collect();
+
+    let old_components = subcomponents(old);
+    let new_components = subcomponents(new);
+    let mut kinds: BTreeSet<&String> = old_components.keys().collect();
+    kinds.extend(new_components.keys());
+    changed.extend(
+        kinds
+            .into_iter()
+            .filter(|kind| old_components.get(*kind) != new_components.get(*kind))
+            .cloned(),
+    );
+
+    changed
+}
+
+/// The subcomponents of `event` by kind, in a stable order.
+fn subcomponents(event: &Event) -> BTreeMap<String, Vec<BTreeMap<String, BTreeSet<String>>>> {
+    let mut components: BTreeMap<String, Vec<_>> = BTreeMap::new();
+    for component in event.components() {
+        components
+            .entry(component.component_kind())
+            .or_default()
+            .push(comparable(component));
+    }
+    for versions in components.values_mut() {
+        versions.sort();
+    }
+    components
+}
+
+fn comparable<C: Component>(component: &C) -> BTreeMap<String, BTreeSet<String>> {
+    let mut properties: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    let all = component
+        .properties()
+        .values()
+        .chain(component.multi_properties().values().flatten());
+    for property in all.filter(|property| !IGNORED_PROPERTIES.contains(&property.key())) {
+        properties
+            .entry(property.key().to_string())
+            .or_default()
+            .insert(canonical(property));
+    }
+    properties
+}
+
+/// The value of a property with its parameters in a stable order.
+fn canonical(property: &Property) -> String {
+    let params: BTreeSet<String> = property
+        .params()
+        .values()
+        .map(|param| format!("{}={}", param.key(), param.value()))
+        .collect();
+
+    format!("{}:{}", params.into_iter().collect::<Vec<_>>().join(";"), property.value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calendar(text: &str) -> Vec<CalendarComponent> {
+        crate::lib::parse::parse_strict(&text.replace('\n', "\r\n")).unwrap().components
+    }
+
+    #[test]
+    fn test_reports_added_removed_and_changed_events() {
+        let old = calendar(
+            "BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:kept
+DTSTAMP:20240101T000000Z
+DTSTART:20240318T090000Z
+SUMMARY:Standup
+END:VEVENT
+BEGIN:VEVENT
+UID:moved
+DTSTART:20240318T100000Z
+SUMMARY:Review
+ATTENDEE;CN=Alice:mailto:alice@example.com
+END:VEVENT
+BEGIN:VEVENT
+UID:cancelled
+DTSTART:20240318T110000Z
+SUMMARY:Lunch
+END:VEVENT
+END:VCALENDAR
+",
+        );
+        let new = calendar(
+            "BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:kept
+DTSTAMP:20240102T000000Z
+DTSTART:20240318T090000Z
+SUMMARY:Standup
+END:VEVENT
+BEGIN:VEVENT
+UID:moved
+DTSTART:20240318T140000Z
+SUMMARY:Review
+ATTENDEE;CN=Alice:mailto:alice@example.com
+ATTENDEE;CN=Bob:mailto:bob@example.com
+END:VEVENT
+BEGIN:VEVENT
+UID:new
+DTSTART:20240319T090000Z
+SUMMARY:Planning
+END:VEVENT
+END:VCALENDAR
+",
+        );
+
+        let changes: Vec<String> = diff_calendars(&old, &new).iter().map(ToString::to_string).collect();
+
+        assert_eq!(
+            changes,
+            vec!["- cancelled Lunch", "~ moved Review: ATTENDEE, DTSTART", "+ new Planning"]
+        );
+    }
+
+    #[test]
+    fn test_keeps_overrides_apart_and_names_subcomponents() {
+        let old = calendar(
+            "BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:series
+DTSTART:20240318T090000Z
+RRULE:FREQ=DAILY
+SUMMARY:Standup
+BEGIN:VALARM
+ACTION:DISPLAY
+TRIGGER:-PT10M
+END:VALARM
+END:VEVENT
+BEGIN:VEVENT
+UID:series
+RECURRENCE-ID:20240319T090000Z
+DTSTART:20240319T100000Z
+SUMMARY:Standup
+END:VEVENT
+END:VCALENDAR
+",
+        );
+        let new = calendar(
+            "BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:series
+DTSTART:20240318T090000Z
+RRULE:FREQ=DAILY
+SUMMARY:Standup
+BEGIN:VALARM
+ACTION:DISPLAY
+TRIGGER:-PT5M
+END:VALARM
+END:VEVENT
+BEGIN:VEVENT
+UID:series
+RECURRENCE-ID:20240319T090000Z
+DTSTART:20240319T110000Z
+SUMMARY:Standup
+BEGIN:X-LOCATION
+NAME:Room 2
+END:X-LOCATION
+END:VEVENT
+BEGIN:VEVENT
+UID:series
+RECURRENCE-ID:20240320T090000Z
+DTSTART:20240320T100000Z
+SUMMARY:Standup
+END:VEVENT
+END:VCALENDAR
+",
+        );
+
+        let changes: Vec<String> = diff_calendars(&old, &new).iter().map(ToString::to_string).collect();
+
+        assert_eq!(
+            changes,
+            vec![
+                "~ series Standup: VALARM",
+                "~ series@20240319T090000Z Standup: DTSTART, X-LOCATION",
+                "+ series@20240320T090000Z Standup",
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use axum::{http::StatusCode, response::IntoResponse};
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -7,12 +9,51 @@ pub enum Error {
     #[error("failed to make request: {0}")]
     Reqwest(#[from] reqwest::Error),
 
+    /// Errors of a source never contain its url, see [`SourceConfig::label`].
+    /// They are named by [`Error::Source`] or the log span instead.
+    ///
+    /// [`SourceConfig::label`]: crate::lib::config::SourceConfig::label
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+
+    #[error("response exceeds the limit of {0} bytes")]
+    ResponseTooLarge(u64),
+
+    #[error("HTTP {status} error: {body}")]
+    HttpStatus {
+        status: u16,
+        retry_after: Option<Duration>,
+        body: String,
+    },
+
+    #[error("failing repeatedly, skipped until the circuit breaker closes")]
+    CircuitOpen,
+
+    /// A source of a feed could not be read, named by its label.
+    #[error("cannot read source {0}: {1}")]
+    Source(String, Box<Error>),
+
     #[error("failed to parse calender: {0}")]
     ParseCalender(String),
 
-    #[error("environment variables could not be validated: {0:#?}")]
+    #[error("environment variables could not be read: {0}")]
     Envy(#[from] envy::Error),
 
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("invalid configuration:{}", .0.iter().map(|problem| format!("\n  - {problem}")).collect::<String>())]
+    InvalidConfig(Vec<String>),
+
+    #[error("no feed named {0}")]
+    FeedNotFound(String),
+
+    #[error("bad request: {0}")]
+    BadRequest(String),
+
+    #[error("cannot read {0}: {1}")]
+    ReadFile(String, std::io::Error),
+
     #[error("cannot bind tcp port: {0}")]
     IO(#[from] std::io::Error),
 
@@ -20,9 +61,60 @@ pub enum Error {
     Eyre(#[from] eyre::Report),
 }
 
+impl Error {
+    /// Whether retrying the same request later has a chance of succeeding.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Reqwest(e) => e.is_connect() || e.is_timeout() || e.is_request() || e.is_body(),
+            Error::HttpStatus { status, .. } => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// A short, stable name for the kind of failure, used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Reqwest(e) if e.is_timeout() => "timeout",
+            Error::Reqwest(e) if e.is_connect() => "connect",
+            Error::Reqwest(_) => "request",
+            Error::InvalidUrl(_) => "invalid_url",
+            Error::ResponseTooLarge(..) => "too_large",
+            Error::HttpStatus { .. } => "http_status",
+            Error::CircuitOpen => "circuit_open",
+            Error::ParseCalender(_) => "parse",
+            Error::Source(_, error) => error.kind(),
+            _ => "other",
+        }
+    }
+
+    /// The delay the upstream asked for via `Retry-After`, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::HttpStatus { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        eprintln!("{self}");
-        (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong").into_response()
+        let (status, body) = match &self {
+            Error::FeedNotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
+            Error::BadRequest(_) => (StatusCode::BAD_REQUEST, self.to_string()),
+            // Only the name of the source is returned, the details may contain urls.
+            Error::Source(name, _) => (StatusCode::BAD_GATEWAY, format!("cannot read source {name}")),
+            Error::Reqwest(_) | Error::HttpStatus { .. } | Error::CircuitOpen => {
+                (StatusCode::BAD_GATEWAY, "Something went wrong".into())
+            }
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong".into()),
+        };
+
+        if status.is_server_error() {
+            tracing::error!(error = %self, kind = self.kind(), "request failed");
+        } else {
+            tracing::debug!(error = %self, "rejected request");
+        }
+
+        (status, body).into_response()
     }
 }
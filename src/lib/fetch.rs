@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cached::{Cached, TimedCache};
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    redirect, Client, NoProxy, Proxy,
+};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+use crate::lib::config::Config;
+use crate::lib::error::{Error, Result};
+use crate::lib::metrics::Metrics;
+use crate::lib::parse::ParseWarning;
+
+/// How much of the body of an error response is kept for diagnostics.
+const ERROR_BODY_LIMIT: u64 = 512;
+
+/// How long a successful response is reused at most, so a cached feed is never
+/// more than this much older than the refresh interval.
+const BODY_REUSE: Duration = Duration::from_secs(60);
+
+/// Shared HTTP client used for all upstream calendar requests.
+///
+/// Keeping a single `reqwest::Client` around lets connections, DNS lookups
+/// and TLS sessions be reused between refreshes.
+pub struct Fetcher {
+    client: Client,
+    max_response_bytes: u64,
+    max_connections_per_host: usize,
+    host_limits: Mutex<HashMap<String, Arc<Semaphore>>>,
+    retry: RetryPolicy,
+    breaker: BreakerPolicy,
+    sources: Mutex<HashMap<String, SourceHealth>>,
+    status: Mutex<HashMap<String, SourceStatus>>,
+    /// Successful responses by url, kept for [`BODY_REUSE`] at most. They
+    /// outlive the rendered feeds, so feeds sharing a source and a config
+    /// reload right after a refresh do not fetch it again, while a feed is
+    /// never built from a response much older than the refresh interval.
+    bodies: Mutex<TimedCache<String, FetchedBody>>,
+    metrics: Arc<Metrics>,
+}
+
+/// Raw body of a successful upstream response.
+#[derive(Clone)]
+pub struct FetchedBody {
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+    pub status: u16,
+    /// When the response was received, which stays the same while it is cached.
+    pub fetched_at: DateTime<Utc>,
+}
+
+struct RetryPolicy {
+    attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+struct BreakerPolicy {
+    threshold: u32,
+    cooldown: Duration,
+}
+
+/// Circuit breaker bookkeeping for a single source url.
+#[derive(Debug, Clone, Default)]
+pub struct SourceHealth {
+    pub consecutive_failures: u32,
+    pub open_until: Option<Instant>,
+}
+
+impl SourceHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.open_until.is_none()
+    }
+}
+
+/// The latest refresh of a source url, as reported by `/status`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SourceStatus {
+    /// Time of the latest request, successful or not.
+    pub last_fetch: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub http_status: Option<u16>,
+    pub bytes: Option<usize>,
+    /// Components of the calendar, mostly events.
+    pub events: Option<usize>,
+    pub parse_warnings: Vec<ParseWarning>,
+    pub consecutive_failures: u32,
+    pub healthy: bool,
+    pub last_error: Option<String>,
+}
+
+impl Fetcher {
+    pub fn new(config: &Config) -> Result<Self> {
+        Self::with_metrics(config, Arc::new(Metrics::new()))
+    }
+
+    /// Like [`Fetcher::new`], but records into `metrics`, e.g. the ones served
+    /// at `/metrics`.
+    pub fn with_metrics(config: &Config, metrics: Arc<Metrics>) -> Result<Self> {
+        let mut builder = Client::builder()
+            .user_agent(&config.user_agent)
+            .timeout(Duration::from_secs(config.http_timeout_secs))
+            .redirect(redirect::Policy::limited(config.http_max_redirects))
+            .pool_max_idle_per_host(config.http_max_connections_per_host);
+
+        // Without an explicit proxy, reqwest picks up HTTP_PROXY, HTTPS_PROXY
+        // and NO_PROXY from the environment on its own.
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?.no_proxy(NoProxy::from_env()));
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            max_response_bytes: config.http_max_response_bytes,
+            max_connections_per_host: config.http_max_connections_per_host.max(1),
+            host_limits: Mutex::new(HashMap::new()),
+            retry: RetryPolicy {
+                attempts: config.retry_attempts,
+                base_delay: Duration::from_millis(config.retry_base_delay_ms),
+                max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            },
+            breaker: BreakerPolicy {
+                threshold: config.circuit_breaker_threshold.max(1),
+                cooldown: Duration::from_secs(config.circuit_breaker_cooldown_secs),
+            },
+            sources: Mutex::new(HashMap::new()),
+            status: Mutex::new(HashMap::new()),
+            bodies: Mutex::new(TimedCache::with_lifespan(
+                Duration::from_secs(config.refresh_interval_secs).min(BODY_REUSE),
+            )),
+            metrics,
+        })
+    }
+
+    /// Fetches `url`, retrying transient failures with exponential backoff.
+    ///
+    /// Sources that keep failing trip their circuit breaker and are not
+    /// contacted again until the cooldown has passed. Successful responses
+    /// are reused for up to a minute.
+    /// `source` names the source in the metrics.
+    pub async fn fetch(&self, source: &str, url: &str) -> Result<FetchedBody> {
+        let cached = self.bodies.lock().unwrap().cache_get(url).cloned();
+        self.metrics.count_cache("source", cached.is_some());
+        if let Some(body) = cached {
+            return Ok(body);
+        }
+
+        if !self.circuit_allows(url) {
+            return Err(Error::CircuitOpen);
+        }
+
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let result = self.fetch_once(url).await;
+            self.metrics.observe_fetch(source, started.elapsed().as_secs_f64());
+
+            match result {
+                Ok(body) => {
+                    self.record_success(url);
+                    self.bodies.lock().unwrap().cache_set(url.to_string(), body.clone());
+                    return Ok(body);
+                }
+                Err(err) if err.is_transient() && attempt < self.retry.attempts => {
+                    let delay = err
+                        .retry_after()
+                        .map(|delay| delay.min(self.retry.max_delay))
+                        .unwrap_or_else(|| self.backoff(attempt));
+
+                    tracing::info!(attempt = attempt + 1, ?delay, error = %err, "request failed, retrying");
+
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                Err(err) => {
+                    self.record_failure(url);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Health of every source that has been fetched so far.
+    pub fn source_health(&self) -> HashMap<String, SourceHealth> {
+        self.sources.lock().unwrap().clone()
+    }
+
+    /// Records a source that was fetched and parsed.
+    pub fn record_source_success(&self, url: &str, body: &FetchedBody, events: usize, warnings: Vec<ParseWarning>) {
+        let mut status = self.status.lock().unwrap();
+        let status = status.entry(url.to_string()).or_default();
+
+        status.last_fetch = Some(body.fetched_at);
+        status.last_success = Some(body.fetched_at);
+        status.http_status = Some(body.status);
+        status.bytes = Some(body.bytes.len());
+        status.events = Some(events);
+        status.parse_warnings = warnings;
+        status.last_error = None;
+    }
+
+    /// Records a source that could not be fetched or, with `body`, not parsed.
+    pub fn record_source_error(&self, source: &str, url: &str, body: Option<&FetchedBody>, error: &Error) {
+        self.metrics.count_fetch_error(source, error);
+
+        let mut status = self.status.lock().unwrap();
+        let status = status.entry(url.to_string()).or_default();
+
+        // Skipped sources were not contacted at all.
+        if !matches!(error, Error::CircuitOpen) {
+            status.last_fetch = Some(body.map_or_else(Utc::now, |body| body.fetched_at));
+            status.http_status = body.map(|body| body.status).or(match error {
+                Error::HttpStatus { status, .. } => Some(*status),
+                _ => None,
+            });
+            status.bytes = body.map(|body| body.bytes.len());
+            status.events = None;
+        }
+        status.last_error = Some(error.to_string());
+    }
+
+    /// The latest refresh of the source at `url`, empty if it has not been
+    /// fetched yet.
+    pub fn source_status(&self, url: &str) -> SourceStatus {
+        let health = self.sources.lock().unwrap().get(url).cloned().unwrap_or_default();
+        let status = self.status.lock().unwrap().get(url).cloned().unwrap_or_default();
+
+        SourceStatus {
+            consecutive_failures: health.consecutive_failures,
+            healthy: health.is_healthy(),
+            ..status
+        }
+    }
+
+    /// Whether any source has been read successfully since the start.
+    pub fn has_succeeded(&self) -> bool {
+        self.status.lock().unwrap().values().any(|status| status.last_success.is_some())
+    }
+
+    /// Exponential backoff with full jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .retry
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.retry.max_delay);
+
+        Duration::from_millis(rand::random_range(0..=exponential.as_millis() as u64))
+    }
+
+    fn circuit_allows(&self, url: &str) -> bool {
+        let mut sources = self.sources.lock().unwrap();
+        let Some(health) = sources.get_mut(url) else {
+            return true;
+        };
+
+        match health.open_until {
+            Some(open_until) if Instant::now() < open_until => false,
+            Some(_) => {
+                // Cooldown is over, let a single probe through. Another failure
+                // reopens the circuit straight away.
+                health.open_until = None;
+                health.consecutive_failures = self.breaker.threshold - 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record_success(&self, url: &str) {
+        self.sources.lock().unwrap().insert(url.to_string(), SourceHealth::default());
+    }
+
+    fn record_failure(&self, url: &str) {
+        let mut sources = self.sources.lock().unwrap();
+        let health = sources.entry(url.to_string()).or_default();
+
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.breaker.threshold {
+            tracing::warn!(
+                failures = health.consecutive_failures,
+                cooldown = ?self.breaker.cooldown,
+                "source keeps failing, marking it unhealthy"
+            );
+            health.open_until = Some(Instant::now() + self.breaker.cooldown);
+        }
+    }
+
+    fn host_limit(&self, url: &reqwest::Url) -> Arc<Semaphore> {
+        let host = url.host_str().unwrap_or_default().to_string();
+        let mut host_limits = self.host_limits.lock().unwrap();
+
+        host_limits
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_connections_per_host)))
+            .clone()
+    }
+
+    async fn fetch_once(&self, url: &str) -> Result<FetchedBody> {
+        let parsed_url = reqwest::Url::parse(url).map_err(|e| Error::InvalidUrl(e.to_string()))?;
+
+        let host_limit = self.host_limit(&parsed_url);
+        let _permit = host_limit.acquire().await.expect("host semaphore is never closed");
+
+        let mut res = self
+            .client
+            .get(parsed_url)
+            .header("Accept", "text/calendar,application/calendar,text/plain,*/*")
+            .header("Accept-Language", "en-US,en;q=0.9")
+            .header("Cache-Control", "no-cache")
+            .send()
+            .await
+            .map_err(|e| Error::Reqwest(e.without_url()))?;
+
+        if !res.status().is_success() {
+            let retry_after = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+
+            let status = res.status().as_u16();
+            let body = match read_body(&mut res, ERROR_BODY_LIMIT).await {
+                Ok((bytes, truncated)) => error_body(&bytes, truncated),
+                Err(_) => "Unknown error".to_string(),
+            };
+
+            return Err(Error::HttpStatus {
+                status,
+                retry_after,
+                body,
+            });
+        }
+
+        // Reject oversized responses up front if the server tells us the size,
+        // otherwise stop reading as soon as the limit is crossed.
+        if res.content_length().is_some_and(|len| len > self.max_response_bytes) {
+            return Err(Error::ResponseTooLarge(self.max_response_bytes));
+        }
+
+        let (body, truncated) = read_body(&mut res, self.max_response_bytes).await?;
+        if truncated {
+            return Err(Error::ResponseTooLarge(self.max_response_bytes));
+        }
+
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        Ok(FetchedBody {
+            bytes: body,
+            content_type,
+            status: res.status().as_u16(),
+            fetched_at: Utc::now(),
+        })
+    }
+}
+
+/// Reads at most `limit` bytes of the body and whether there was more.
+async fn read_body(res: &mut reqwest::Response, limit: u64) -> Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await.map_err(|e| Error::Reqwest(e.without_url()))? {
+        let room = (limit - body.len() as u64) as usize;
+        if chunk.len() > room {
+            body.extend_from_slice(&chunk[..room]);
+            return Ok((body, true));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, false))
+}
+
+/// The start of an error response, as kept in [`Error::HttpStatus`].
+fn error_body(bytes: &[u8], truncated: bool) -> String {
+    let mut body = String::from_utf8_lossy(bytes).trim().to_string();
+    if truncated {
+        body.push_str(" [truncated]");
+    }
+    body
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetcher(vars: &[(&str, &str)]) -> Fetcher {
+        let vars = vars.iter().map(|(key, value)| (key.to_string(), value.to_string()));
+        let config: Config = envy::from_iter(vars).unwrap();
+        Fetcher::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_responses_are_reused_for_at_most_a_minute() {
+        let lifespan = |refresh: &str| fetcher(&[("REFRESH_INTERVAL_SECS", refresh)]).bodies.lock().unwrap().cache_lifespan();
+
+        assert_eq!(lifespan("900"), Some(BODY_REUSE));
+        assert_eq!(lifespan("10"), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_backoff_stays_below_the_capped_exponential_delay() {
+        let fetcher = fetcher(&[("RETRY_BASE_DELAY_MS", "100"), ("RETRY_MAX_DELAY_MS", "1000")]);
+
+        for _ in 0..50 {
+            assert!(fetcher.backoff(0) <= Duration::from_millis(100));
+            assert!(fetcher.backoff(2) <= Duration::from_millis(400));
+            assert!(fetcher.backoff(20) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn test_parses_retry_after_seconds_and_dates() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("soon"), None);
+
+        let later = (Utc::now() + chrono::Duration::minutes(5)).to_rfc2822();
+        let delay = parse_retry_after(&later).unwrap();
+        assert!(delay > Duration::from_secs(290) && delay <= Duration::from_secs(300));
+        // A date in the past means no delay to honour.
+        assert_eq!(parse_retry_after("Tue, 1 Jul 2003 10:52:37 +0200"), None);
+    }
+
+    #[test]
+    fn test_circuit_opens_after_repeated_failures() {
+        let url = "https://example.com/a.ics";
+        let fetcher = fetcher(&[("CIRCUIT_BREAKER_THRESHOLD", "2"), ("CIRCUIT_BREAKER_COOLDOWN_SECS", "60")]);
+
+        fetcher.record_failure(url);
+        assert!(fetcher.circuit_allows(url));
+        fetcher.record_failure(url);
+        assert!(!fetcher.circuit_allows(url));
+        assert!(!fetcher.source_health()[url].is_healthy());
+
+        fetcher.record_success(url);
+        assert!(fetcher.circuit_allows(url));
+        assert!(fetcher.source_health()[url].is_healthy());
+    }
+
+    #[test]
+    fn test_circuit_lets_one_probe_through_after_the_cooldown() {
+        let url = "https://example.com/a.ics";
+        let fetcher = fetcher(&[("CIRCUIT_BREAKER_THRESHOLD", "3"), ("CIRCUIT_BREAKER_COOLDOWN_SECS", "0")]);
+
+        for _ in 0..3 {
+            fetcher.record_failure(url);
+        }
+        assert!(fetcher.circuit_allows(url));
+        // A failing probe reopens the circuit straight away.
+        fetcher.record_failure(url);
+        assert!(fetcher.source_health()[url].open_until.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_responses_over_the_size_limit() {
+        use axum::{body::Body, routing::get, Router};
+
+        let chunks = || futures::stream::iter((0..4).map(|_| Ok::<_, std::io::Error>(vec![b'x'; 100])));
+        let app = Router::new()
+            .route("/sized.ics", get(|| async { "x".repeat(400) }))
+            .route("/streamed.ics", get(move || async move { Body::from_stream(chunks()) }))
+            .route("/small.ics", get(|| async { "x".repeat(100) }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let fetcher = fetcher(&[("HTTP_MAX_RESPONSE_BYTES", "300"), ("RETRY_ATTEMPTS", "0")]);
+        let url = |path: &str| format!("http://{address}/{path}");
+
+        // Rejected up front by Content-Length, and while reading a chunked body.
+        assert!(matches!(fetcher.fetch_once(&url("sized.ics")).await, Err(Error::ResponseTooLarge(300))));
+        assert!(matches!(fetcher.fetch_once(&url("streamed.ics")).await, Err(Error::ResponseTooLarge(300))));
+        assert_eq!(fetcher.fetch_once(&url("small.ics")).await.unwrap().bytes.len(), 100);
+    }
+
+    #[test]
+    fn test_error_bodies_are_marked_when_truncated() {
+        assert_eq!(error_body(b" Not Found\n", false), "Not Found");
+        assert_eq!(error_body(b"<html>", true), "<html> [truncated]");
+    }
+
+    #[test]
+    fn test_status_keeps_the_last_success_after_a_failure() {
+        let fetcher = fetcher(&[]);
+        let url = "https://example.com/a.ics";
+        assert!(!fetcher.has_succeeded());
+
+        let body = FetchedBody {
+            bytes: b"BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n".to_vec(),
+            content_type: None,
+            status: 200,
+            fetched_at: Utc::now(),
+        };
+        fetcher.record_source_success(url, &body, 3, Vec::new());
+        fetcher.record_failure(url);
+        fetcher.record_source_error(
+            "example.com",
+            url,
+            None,
+            &Error::HttpStatus {
+                status: 503,
+                retry_after: None,
+                body: String::new(),
+            },
+        );
+
+        let status = fetcher.source_status(url);
+        assert_eq!(status.last_success, Some(body.fetched_at));
+        assert_eq!((status.http_status, status.events, status.consecutive_failures), (Some(503), None, 1));
+        assert!(status.last_error.as_deref().unwrap().starts_with("HTTP 503"));
+        assert!(fetcher.has_succeeded());
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Weekday};
+use chrono_tz::Tz;
+use icalendar::{CalendarComponent, CalendarDateTime, Component, DatePerhapsTime, Event, EventLike};
+use regex::Regex;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer};
+
+use crate::lib::recurrence::event_duration;
+use crate::lib::timezone::resolve_utc;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    Include,
+    Exclude,
+}
+
+/// A single filter rule, e.g. `{ action = "exclude", summary = "(?i)^lunch$" }`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "RawFilterRule")]
+pub struct FilterRule {
+    pub action: FilterAction,
+    pub matcher: EventMatcher,
+}
+
+/// serde ignores `deny_unknown_fields` on flattened structs, so keys that are
+/// neither the action nor a condition are collected and rejected here.
+#[derive(Deserialize)]
+struct RawFilterRule {
+    action: FilterAction,
+    #[serde(flatten)]
+    matcher: EventMatcher,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>,
+}
+
+impl TryFrom<RawFilterRule> for FilterRule {
+    type Error = String;
+
+    fn try_from(raw: RawFilterRule) -> Result<Self, Self::Error> {
+        match raw.unknown.keys().next() {
+            Some(key) => Err(format!("unknown field `{key}` in filter rule")),
+            None => Ok(FilterRule {
+                action: raw.action,
+                matcher: raw.matcher,
+            }),
+        }
+    }
+}
+
+/// Conditions on a single event. All conditions that are set have to hold,
+/// a matcher without any condition matches every event.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct EventMatcher {
+    #[serde(default, deserialize_with = "deserialize_optional_regex")]
+    pub summary: Option<Regex>,
+
+    #[serde(default, deserialize_with = "deserialize_optional_regex")]
+    pub description: Option<Regex>,
+
+    #[serde(default, deserialize_with = "deserialize_optional_regex")]
+    pub location: Option<Regex>,
+
+    /// Matches if the event has any of these categories, ignoring case.
+    #[serde(default)]
+    pub categories: Vec<String>,
+
+    /// Matches if the STATUS is any of these, ignoring case. Events without a
+    /// STATUS are treated as `CONFIRMED`.
+    #[serde(default)]
+    pub status: Vec<String>,
+
+    #[serde(default)]
+    pub min_duration_mins: Option<i64>,
+
+    #[serde(default)]
+    pub max_duration_mins: Option<i64>,
+
+    #[serde(default)]
+    pub all_day: Option<bool>,
+
+    /// Weekdays the event starts on, e.g. `["sat", "sun"]`.
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+
+    /// The event starts at or after this time of day, e.g. `"18:00"`.
+    #[serde(default)]
+    pub starts_after: Option<NaiveTime>,
+
+    /// The event starts before this time of day.
+    #[serde(default)]
+    pub starts_before: Option<NaiveTime>,
+}
+
+impl EventMatcher {
+    /// Weekday and time of day are taken from DTSTART as written in the event,
+    /// in its own timezone.
+    pub fn matches(&self, event: &Event) -> bool {
+        let text_matches = |regex: &Option<Regex>, value: Option<&str>| {
+            regex
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(value.unwrap_or_default()))
+        };
+
+        if !text_matches(&self.summary, event.get_summary())
+            || !text_matches(&self.description, event.get_description())
+            || !text_matches(&self.location, event.get_location())
+        {
+            return false;
+        }
+
+        if !self.categories.is_empty() {
+            let categories = categories(event);
+            let any = self
+                .categories
+                .iter()
+                .any(|wanted| categories.iter().any(|c| c.eq_ignore_ascii_case(wanted)));
+            if !any {
+                return false;
+            }
+        }
+
+        if !self.status.is_empty() {
+            let status = event.property_value("STATUS").unwrap_or("CONFIRMED");
+            if !self.status.iter().any(|wanted| wanted.eq_ignore_ascii_case(status)) {
+                return false;
+            }
+        }
+
+        let start = event.get_start();
+        let is_all_day = matches!(start, Some(DatePerhapsTime::Date(_)));
+
+        if self.all_day.is_some_and(|all_day| all_day != is_all_day) {
+            return false;
+        }
+
+        if self.min_duration_mins.is_some() || self.max_duration_mins.is_some() {
+            let Some(duration) = event_length(event) else {
+                return false;
+            };
+            if self.min_duration_mins.is_some_and(|min| duration < Duration::minutes(min))
+                || self.max_duration_mins.is_some_and(|max| duration > Duration::minutes(max))
+            {
+                return false;
+            }
+        }
+
+        if !self.weekdays.is_empty() || self.starts_after.is_some() || self.starts_before.is_some() {
+            let Some(start) = start.as_ref().and_then(wall_clock_start) else {
+                return false;
+            };
+            if !self.weekdays.is_empty() && !self.weekdays.contains(&start.weekday()) {
+                return false;
+            }
+            if self.starts_after.is_some_and(|after| start.time() < after)
+                || self.starts_before.is_some_and(|before| start.time() >= before)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Decides whether an event is kept.
+///
+/// If there are include rules, an event has to match at least one of them.
+/// An event matching any exclude rule is always dropped.
+pub fn is_included(rules: &[FilterRule], event: &Event) -> bool {
+    let mut includes = rules.iter().filter(|rule| rule.action == FilterAction::Include).peekable();
+    let included = includes.peek().is_none() || includes.any(|rule| rule.matcher.matches(event));
+
+    included
+        && !rules
+            .iter()
+            .filter(|rule| rule.action == FilterAction::Exclude)
+            .any(|rule| rule.matcher.matches(event))
+}
+
+/// Drops the events rejected by `rules`, other components are kept.
+pub fn apply_filters(components: Vec<CalendarComponent>, rules: &[FilterRule]) -> Vec<CalendarComponent> {
+    if rules.is_empty() {
+        return components;
+    }
+
+    components
+        .into_iter()
+        .filter(|component| match component {
+            CalendarComponent::Event(event) => is_included(rules, event),
+            _ => true,
+        })
+        .collect()
+}
+
+pub fn categories(event: &Event) -> Vec<String> {
+    event
+        .multi_properties()
+        .get("CATEGORIES")
+        .into_iter()
+        .flatten()
+        .flat_map(|property| property.value().split(','))
+        .map(|category| category.trim().to_string())
+        .collect()
+}
+
+fn event_length(event: &Event) -> Option<Duration> {
+    let start = event.get_start()?;
+    let Some(end) = event.get_end() else {
+        if let Some(duration) = event_duration(event) {
+            return Some(duration);
+        }
+        // RFC 5545: without DTEND or DURATION, an all-day event lasts one day, others are instantaneous.
+        return Some(match start {
+            DatePerhapsTime::Date(_) => Duration::days(1),
+            DatePerhapsTime::DateTime(_) => Duration::zero(),
+        });
+    };
+
+    Some(resolve_utc(&end, Tz::UTC)? - resolve_utc(&start, Tz::UTC)?)
+}
+
+fn wall_clock_start(start: &DatePerhapsTime) -> Option<NaiveDateTime> {
+    match start {
+        DatePerhapsTime::DateTime(CalendarDateTime::Floating(naive)) => Some(*naive),
+        DatePerhapsTime::DateTime(CalendarDateTime::Utc(utc)) => Some(utc.naive_utc()),
+        DatePerhapsTime::DateTime(CalendarDateTime::WithTimezone { date_time, .. }) => Some(*date_time),
+        DatePerhapsTime::Date(date) => date.and_hms_opt(0, 0, 0),
+    }
+}
+
+/// Reads a regular expression, so invalid patterns are reported with the config.
+pub fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    Regex::new(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    deserialize_regex(deserializer).map(Some)
+}
+
+/// Reads the rules of a TOML table with a single array, like `[[filters]]`.
+#[cfg(test)]
+pub fn parse_rules<T: serde::de::DeserializeOwned>(toml: &str) -> Vec<T> {
+    toml::from_str::<BTreeMap<String, Vec<T>>>(toml)
+        .unwrap()
+        .into_values()
+        .next()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn timed(summary: &str, day: u32, hour: u32, minutes: i64) -> Event {
+        let start = NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+            .and_utc();
+
+        Event::new()
+            .summary(summary)
+            .starts(start)
+            .ends(start + Duration::minutes(minutes))
+            .done()
+    }
+
+    #[test]
+    fn test_exclude_by_summary_and_duration() {
+        let rules = parse_rules(
+            r#"
+            [[filters]]
+            action = "exclude"
+            summary = "(?i)^lunch$"
+
+            [[filters]]
+            action = "exclude"
+            max_duration_mins = 4
+            "#,
+        );
+
+        assert!(!is_included(&rules, &timed("Lunch", 1, 12, 60)));
+        assert!(!is_included(&rules, &timed("Ping", 1, 9, 3)));
+        assert!(is_included(&rules, &timed("Lunch with Bob", 1, 12, 60)));
+    }
+
+    #[test]
+    fn test_duration_counts_without_dtend() {
+        let rules = parse_rules(
+            r#"
+            [[filters]]
+            action = "exclude"
+            max_duration_mins = 4
+            "#,
+        );
+        let text = "BEGIN:VEVENT\r\nUID:a\r\nSUMMARY:Workshop\r\nDTSTART:20240101T090000Z\r\n\
+                    DURATION:PT1H\r\nEND:VEVENT\r\n";
+        let CalendarComponent::Event(event) = text.parse::<CalendarComponent>().unwrap() else {
+            panic!("Expected event component");
+        };
+
+        assert!(is_included(&rules, &event));
+    }
+
+    #[test]
+    fn test_all_day_and_categories() {
+        let rules = parse_rules(
+            r#"
+            [[filters]]
+            action = "exclude"
+            summary = "Out of office"
+            all_day = true
+            "#,
+        );
+        let all_day = Event::new()
+            .summary("Out of office")
+            .all_day(NaiveDate::from_ymd_opt(2024, 1, 1).unwrap())
+            .done();
+
+        assert!(!is_included(&rules, &all_day));
+        assert!(is_included(&rules, &timed("Out of office", 1, 9, 60)));
+
+        let rules = parse_rules(
+            r#"
+            [[filters]]
+            action = "include"
+            categories = ["work"]
+            "#,
+        );
+        let mut tagged = timed("Review", 1, 9, 60);
+        tagged.append_multi_property(("CATEGORIES", "Work,Important"));
+
+        assert!(is_included(&rules, &tagged));
+        assert!(!is_included(&rules, &timed("Review", 1, 9, 60)));
+    }
+
+    #[test]
+    fn test_weekday_and_time_of_day() {
+        let rules = parse_rules(
+            r#"
+            [[filters]]
+            action = "include"
+            weekdays = ["mon", "tue", "wed", "thu", "fri"]
+            starts_after = "08:00"
+            starts_before = "18:00"
+            "#,
+        );
+
+        // 2024-01-01 is a Monday, 2024-01-06 a Saturday.
+        assert!(is_included(&rules, &timed("Standup", 1, 9, 15)));
+        assert!(!is_included(&rules, &timed("Dinner", 1, 19, 60)));
+        assert!(!is_included(&rules, &timed("Hike", 6, 9, 180)));
+    }
+}
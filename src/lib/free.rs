@@ -0,0 +1,321 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use icalendar::{Calendar, CalendarComponent, Component, Event, EventLike};
+use serde::{Deserialize, Serialize};
+
+use crate::lib::busy::BusySlot;
+use crate::lib::recurrence::checked_seconds;
+use crate::lib::timezone::local_to_utc;
+
+/// Where free slots are looked for.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FreeOptions {
+    /// Working hours of feeds without an availability, which otherwise decides them.
+    #[serde(default = "default_working_hours_start")]
+    pub working_hours_start: NaiveTime,
+
+    #[serde(default = "default_working_hours_end")]
+    pub working_hours_end: NaiveTime,
+
+    #[serde(default = "default_working_days")]
+    pub working_days: Vec<Weekday>,
+
+    /// Shorter free slots are not offered, even if the requested duration would fit.
+    #[serde(default = "default_min_slot_mins")]
+    pub min_slot_mins: u32,
+
+    /// Slots start at a multiple of this many minutes after midnight.
+    #[serde(default = "default_align_mins")]
+    pub align_mins: u32,
+}
+
+impl Default for FreeOptions {
+    fn default() -> Self {
+        FreeOptions {
+            working_hours_start: default_working_hours_start(),
+            working_hours_end: default_working_hours_end(),
+            working_days: default_working_days(),
+            min_slot_mins: default_min_slot_mins(),
+            align_mins: default_align_mins(),
+        }
+    }
+}
+
+fn default_working_hours_start() -> NaiveTime {
+    NaiveTime::from_hms_opt(9, 0, 0).unwrap()
+}
+
+fn default_working_hours_end() -> NaiveTime {
+    NaiveTime::from_hms_opt(17, 0, 0).unwrap()
+}
+
+fn default_working_days() -> Vec<Weekday> {
+    vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri]
+}
+
+fn default_min_slot_mins() -> u32 {
+    15
+}
+
+fn default_align_mins() -> u32 {
+    15
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeSlot {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Free slots within the `working` periods that are at least `duration` long.
+/// `busy` has to be sorted, as returned by [`crate::lib::busy::busy_slots`].
+pub fn free_slots(
+    busy: &[BusySlot],
+    working: &[(DateTime<Utc>, DateTime<Utc>)],
+    tz: Tz,
+    options: &FreeOptions,
+    duration: Duration,
+) -> Vec<FreeSlot> {
+    let min_length = duration.max(Duration::minutes(options.min_slot_mins.into()));
+    let mut slots = Vec::new();
+
+    for &(day_start, day_end) in working {
+        let mut cursor = day_start;
+
+        for slot in busy.iter().filter(|slot| slot.end > day_start && slot.start < day_end) {
+            push_slot(&mut slots, cursor, slot.start.min(day_end), tz, options, min_length);
+            cursor = cursor.max(slot.end);
+        }
+        push_slot(&mut slots, cursor, day_end, tz, options, min_length);
+    }
+
+    slots
+}
+
+/// Working hours of every day in `[from, to)` in `tz`, clipped to that range.
+pub fn working_periods(
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    tz: Tz,
+    options: &FreeOptions,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let first_day = from.with_timezone(&tz).date_naive();
+    let last_day = to.with_timezone(&tz).date_naive();
+
+    first_day
+        .iter_days()
+        .take_while(|day| *day <= last_day)
+        .filter(|day| options.working_days.contains(&day.weekday()))
+        .filter_map(|day| {
+            let start = local_to_utc(day.and_time(options.working_hours_start), tz)?.max(from);
+            let end = local_to_utc(day.and_time(options.working_hours_end), tz)?.min(to);
+            (start < end).then_some((start, end))
+        })
+        .collect()
+}
+
+fn push_slot(
+    slots: &mut Vec<FreeSlot>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    tz: Tz,
+    options: &FreeOptions,
+    min_length: Duration,
+) {
+    let start = align(start, tz, options.align_mins);
+    if end - start >= min_length {
+        slots.push(FreeSlot { start, end });
+    }
+}
+
+/// Rounds `time` up to the next multiple of `minutes` after local midnight.
+fn align(time: DateTime<Utc>, tz: Tz, minutes: u32) -> DateTime<Utc> {
+    let step = i64::from(minutes) * 60;
+    if step == 0 {
+        return time;
+    }
+
+    let since_midnight = i64::from(time.with_timezone(&tz).num_seconds_from_midnight());
+    let remainder = since_midnight % step;
+    if remainder == 0 {
+        time
+    } else {
+        time + Duration::seconds(step - remainder)
+    }
+}
+
+/// Parses positive durations like `30m`, `1h`, `1h30m` or a plain number of minutes.
+pub fn parse_duration_param(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(minutes) = value.parse::<u32>() {
+        return (minutes > 0).then(|| Duration::minutes(minutes.into()));
+    }
+
+    let mut total = Duration::zero();
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let amount: i64 = rest[..digits].parse().ok()?;
+        let mut units = rest[digits..].chars();
+        let seconds = match units.next()? {
+            'd' => 86_400,
+            'h' => 3_600,
+            'm' => 60,
+            _ => return None,
+        };
+        total = total.checked_add(&checked_seconds(amount, seconds)?)?;
+        rest = units.as_str();
+    }
+
+    (total > Duration::zero()).then_some(total)
+}
+
+/// Parses a `from`/`to` query parameter, either an RFC 3339 time or a date
+/// meaning midnight in `tz`.
+pub fn parse_time_param(value: &str, tz: Tz) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    local_to_utc(date.and_time(NaiveTime::MIN), tz)
+}
+
+/// A free slot as returned by the JSON API, in the feed's timezone.
+#[derive(Serialize, Debug)]
+pub struct FreeSlotJson {
+    pub start: String,
+    pub end: String,
+    pub minutes: i64,
+}
+
+pub fn slots_to_json(slots: &[FreeSlot], tz: Tz) -> Vec<FreeSlotJson> {
+    slots
+        .iter()
+        .map(|slot| FreeSlotJson {
+            start: slot.start.with_timezone(&tz).to_rfc3339(),
+            end: slot.end.with_timezone(&tz).to_rfc3339(),
+            minutes: (slot.end - slot.start).num_minutes(),
+        })
+        .collect()
+}
+
+/// One transparent "Free" event per slot.
+pub fn slots_to_events(slots: &[FreeSlot]) -> Calendar {
+    slots
+        .iter()
+        .map(|slot| {
+            Event::new()
+                .uid(&format!("free-{}", slot.start.format("%Y%m%dT%H%M%SZ")))
+                .summary("Free")
+                .starts(slot.start)
+                .ends(slot.end)
+                .add_property("TRANSP", "TRANSPARENT")
+                .done()
+        })
+        .collect()
+}
+
+/// A VFREEBUSY component covering `[from, to)` that lists `periods` with their `FBTYPE`.
+pub fn vfreebusy(from: DateTime<Utc>, to: DateTime<Utc>, periods: &[(DateTime<Utc>, DateTime<Utc>, &str)]) -> CalendarComponent {
+    let format = |time: DateTime<Utc>| time.format("%Y%m%dT%H%M%SZ").to_string();
+
+    let mut text = format!(
+        "BEGIN:VFREEBUSY\r\nUID:freebusy-{}-{}\r\nDTSTAMP:{}\r\nDTSTART:{}\r\nDTEND:{}\r\n",
+        format(from),
+        format(to),
+        format(Utc::now()),
+        format(from),
+        format(to),
+    );
+    for (start, end, fbtype) in periods {
+        text.push_str(&format!("FREEBUSY;FBTYPE={fbtype}:{}/{}\r\n", format(*start), format(*end)));
+    }
+    text.push_str("END:VFREEBUSY\r\n");
+
+    text.parse().expect("generated VFREEBUSY is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn busy(start: (u32, u32), end: (u32, u32)) -> BusySlot {
+        BusySlot {
+            start: Utc.with_ymd_and_hms(2024, 3, 15, start.0, start.1, 0).unwrap(),
+            end: Utc.with_ymd_and_hms(2024, 3, 15, end.0, end.1, 0).unwrap(),
+            uid: String::new(),
+            labels: Vec::new(),
+            unavailable: false,
+        }
+    }
+
+    fn times(slots: &[FreeSlot]) -> Vec<String> {
+        slots
+            .iter()
+            .map(|slot| format!("{} {}-{}", slot.start.format("%a"), slot.start.format("%H:%M"), slot.end.format("%H:%M")))
+            .collect()
+    }
+
+    #[test]
+    fn test_inverts_busy_blocks_within_working_hours() {
+        // Friday, 2024-03-15, and the weekend after it.
+        let from = Utc.with_ymd_and_hms(2024, 3, 15, 0, 0, 0).unwrap();
+        let to = from + Duration::days(3);
+        let busy = vec![busy((8, 0), (9, 30)), busy((10, 7), (12, 0)), busy((12, 40), (16, 50))];
+
+        let options = FreeOptions::default();
+
+        let slots = free_slots(&busy, &working_periods(from, to, Tz::UTC, &options), Tz::UTC, &options, Duration::minutes(30));
+
+        // Before 09:30 is outside working hours and 16:50-17:00 is too short.
+        assert_eq!(times(&slots), vec!["Fri 09:30-10:07", "Fri 12:00-12:40"]);
+    }
+
+    #[test]
+    fn test_alignment_and_minimum_length() {
+        let from = Utc.with_ymd_and_hms(2024, 3, 15, 0, 0, 0).unwrap();
+        let to = from + Duration::days(1);
+        let busy = vec![busy((9, 0), (9, 50)), busy((11, 0), (17, 0))];
+        let options = FreeOptions {
+            align_mins: 30,
+            min_slot_mins: 60,
+            ..Default::default()
+        };
+
+        let slots = free_slots(&busy, &working_periods(from, to, Tz::UTC, &options), Tz::UTC, &options, Duration::minutes(15));
+
+        // The gap starts at 09:50, is aligned to 10:00 and is then only an hour long.
+        assert_eq!(times(&slots), vec!["Fri 10:00-11:00"]);
+    }
+
+    #[test]
+    fn test_parse_duration_param() {
+        assert_eq!(parse_duration_param("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration_param("1h30m"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration_param("45"), Some(Duration::minutes(45)));
+        assert_eq!(parse_duration_param("soon"), None);
+        assert_eq!(parse_duration_param("-30"), None);
+        assert_eq!(parse_duration_param("0"), None);
+        assert_eq!(parse_duration_param("-1h"), None);
+        assert_eq!(parse_duration_param("99999999999999d"), None);
+        assert_eq!(parse_duration_param("30é"), None);
+        assert_eq!(parse_duration_param("1h30é"), None);
+    }
+
+    #[test]
+    fn test_parse_time_param() {
+        let berlin = chrono_tz::Europe::Berlin;
+        assert_eq!(
+            parse_time_param("2024-03-15", berlin),
+            Some(Utc.with_ymd_and_hms(2024, 3, 14, 23, 0, 0).unwrap())
+        );
+        assert_eq!(
+            parse_time_param("2024-03-15T10:00:00+02:00", berlin),
+            Some(Utc.with_ymd_and_hms(2024, 3, 15, 8, 0, 0).unwrap())
+        );
+        assert_eq!(parse_time_param("tomorrow", berlin), None);
+    }
+}
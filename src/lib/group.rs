@@ -0,0 +1,261 @@
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use icalendar::{Calendar, CalendarComponent, Component, Event, EventLike};
+use serde::Serialize;
+
+use crate::lib::busy::BusySlot;
+use crate::lib::calendar::{feed_busy_slots, FeedEvents, SOURCE_PROPERTY};
+use crate::lib::config::FeedConfig;
+
+/// The busy slots of one person, i.e. one source of a group feed.
+#[derive(Debug, Clone)]
+pub struct Person {
+    pub name: String,
+    pub busy: Vec<BusySlot>,
+}
+
+impl Person {
+    fn is_busy(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        self.busy.iter().any(|slot| slot.start < end && slot.end > start)
+    }
+}
+
+/// Splits the events of `feed` by source and computes the busy slots of every
+/// source within `[from, to)`, one person per source in the order they are
+/// configured.
+///
+/// Events are told apart by the source name, which config validation makes
+/// distinct for group feeds. `events` should be merged without deduplication,
+/// so a meeting shared by several people keeps them all busy.
+pub fn people(events: &FeedEvents, feed: &FeedConfig, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Person> {
+    feed.sources
+        .iter()
+        .map(|source| {
+            let name = source.label();
+            let components: Vec<CalendarComponent> = events
+                .components
+                .iter()
+                .filter(|component| {
+                    component
+                        .as_event()
+                        .and_then(|event| event.property_value(SOURCE_PROPERTY))
+                        .is_some_and(|source| source == name)
+                })
+                .cloned()
+                .collect();
+
+            let busy = feed_busy_slots(&components, &events.holidays, feed, from, to);
+            Person { name, busy }
+        })
+        .collect()
+}
+
+/// One row of the availability heatmap.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HeatmapSlot {
+    pub start: String,
+    pub end: String,
+    pub busy_count: usize,
+    pub free_count: usize,
+    /// Names of the busy people.
+    pub busy: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Heatmap {
+    pub people: Vec<String>,
+    pub slots: Vec<HeatmapSlot>,
+}
+
+/// Cuts `[from, to)` into steps of `step` and counts who is busy in each of
+/// them. Someone counts as busy if any of their busy slots touches the step.
+pub fn heatmap(people: &[Person], from: DateTime<Utc>, to: DateTime<Utc>, step: Duration, tz: Tz) -> Heatmap {
+    let mut slots = Vec::new();
+    let mut start = from;
+
+    while start < to && step > Duration::zero() {
+        let end = start.checked_add_signed(step).map_or(to, |end| end.min(to));
+        let busy: Vec<String> = people
+            .iter()
+            .filter(|person| person.is_busy(start, end))
+            .map(|person| person.name.clone())
+            .collect();
+
+        slots.push(HeatmapSlot {
+            start: start.with_timezone(&tz).to_rfc3339(),
+            end: end.with_timezone(&tz).to_rfc3339(),
+            busy_count: busy.len(),
+            free_count: people.len() - busy.len(),
+            busy,
+        });
+        start = end;
+    }
+
+    Heatmap {
+        people: people.iter().map(|person| person.name.clone()).collect(),
+        slots,
+    }
+}
+
+/// A span of time in which the same people are free.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupFreePeriod {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub free: Vec<String>,
+}
+
+/// Periods within `[from, to)` in which at least `min_free` people are free.
+/// Adjacent periods are joined as long as the same people are free.
+pub fn group_free_periods(people: &[Person], from: DateTime<Utc>, to: DateTime<Utc>, min_free: usize) -> Vec<GroupFreePeriod> {
+    let mut boundaries: Vec<DateTime<Utc>> = people
+        .iter()
+        .flat_map(|person| person.busy.iter().flat_map(|slot| [slot.start, slot.end]))
+        .filter(|time| *time > from && *time < to)
+        .chain([from, to])
+        .collect();
+    boundaries.sort();
+    boundaries.dedup();
+
+    let mut periods: Vec<GroupFreePeriod> = Vec::new();
+    for pair in boundaries.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let free: Vec<String> = people
+            .iter()
+            .filter(|person| !person.is_busy(start, end))
+            .map(|person| person.name.clone())
+            .collect();
+        if free.len() < min_free.max(1) {
+            continue;
+        }
+
+        match periods.last_mut() {
+            Some(last) if last.end == start && last.free == free => last.end = end,
+            _ => periods.push(GroupFreePeriod { start, end, free }),
+        }
+    }
+
+    periods
+}
+
+/// One transparent event per period, "Everyone free" where nobody is busy.
+pub fn group_free_calendar(periods: &[GroupFreePeriod], total: usize) -> Calendar {
+    periods
+        .iter()
+        .map(|period| {
+            let summary = if period.free.len() == total {
+                "Everyone free".to_string()
+            } else {
+                format!("{} of {total} free", period.free.len())
+            };
+
+            Event::new()
+                .uid(&format!("group-free-{}", period.start.format("%Y%m%dT%H%M%SZ")))
+                .summary(&summary)
+                .description(&format!("Free: {}", period.free.join(", ")))
+                .starts(period.start)
+                .ends(period.end)
+                .add_property("TRANSP", "TRANSPARENT")
+                .done()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 18, hour, 0, 0).unwrap()
+    }
+
+    fn person(name: &str, busy: &[(u32, u32)]) -> Person {
+        Person {
+            name: name.into(),
+            busy: busy
+                .iter()
+                .map(|(start, end)| BusySlot {
+                    start: at(*start),
+                    end: at(*end),
+                    uid: String::new(),
+                    labels: Vec::new(),
+                    unavailable: false,
+                })
+                .collect(),
+        }
+    }
+
+    fn group() -> Vec<Person> {
+        vec![person("Alice", &[(9, 11)]), person("Bob", &[(10, 12)]), person("Carol", &[])]
+    }
+
+    #[test]
+    fn test_people_on_the_same_host_stay_apart() {
+        let feed: FeedConfig = toml::from_str(
+            r#"
+            name = "team"
+            group = true
+            sources = [
+                { url = "https://calendar.google.com/alice.ics", name = "Alice" },
+                { url = "https://calendar.google.com/bob.ics", name = "Bob" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let meeting = |source: &str, hour: u32| -> CalendarComponent {
+            Event::new()
+                .starts(at(hour))
+                .ends(at(hour + 1))
+                .add_property(SOURCE_PROPERTY, source)
+                .done()
+                .into()
+        };
+        let events = FeedEvents {
+            components: vec![meeting("Alice", 9), meeting("Bob", 13)],
+            holidays: Vec::new(),
+        };
+
+        let people = people(&events, &feed, at(0), at(23));
+
+        let busy: Vec<(String, Vec<DateTime<Utc>>)> = people
+            .into_iter()
+            .map(|person| (person.name, person.busy.iter().map(|slot| slot.start).collect()))
+            .collect();
+        assert_eq!(busy, vec![("Alice".to_string(), vec![at(9)]), ("Bob".to_string(), vec![at(13)])]);
+    }
+
+    #[test]
+    fn test_heatmap_counts_busy_people_per_step() {
+        let heatmap = heatmap(&group(), at(9), at(12), Duration::hours(1), Tz::UTC);
+
+        let counts: Vec<(usize, Vec<String>)> = heatmap.slots.into_iter().map(|slot| (slot.busy_count, slot.busy)).collect();
+        assert_eq!(
+            counts,
+            vec![
+                (1, vec!["Alice".to_string()]),
+                (2, vec!["Alice".to_string(), "Bob".to_string()]),
+                (1, vec!["Bob".to_string()]),
+            ]
+        );
+        assert_eq!(heatmap.people, vec!["Alice", "Bob", "Carol"]);
+
+        // A step running past the end of time ends with the range.
+        let heatmap = super::heatmap(&group(), at(9), at(12), Duration::days(99_999_999), Tz::UTC);
+        assert_eq!(heatmap.slots.len(), 1);
+    }
+
+    #[test]
+    fn test_everyone_and_at_least_n_free() {
+        let spans = |periods: Vec<GroupFreePeriod>| -> Vec<(u32, u32, usize)> {
+            use chrono::Timelike;
+            periods.iter().map(|p| (p.start.hour(), p.end.hour(), p.free.len())).collect()
+        };
+
+        assert_eq!(spans(group_free_periods(&group(), at(8), at(14), 3)), vec![(8, 9, 3), (12, 14, 3)]);
+        assert_eq!(
+            spans(group_free_periods(&group(), at(8), at(14), 2)),
+            vec![(8, 9, 3), (9, 10, 2), (11, 12, 2), (12, 14, 3)]
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::lib::error::Error;
+
+/// Prometheus metrics of the server and the upstream fetches.
+pub struct Metrics {
+    registry: Registry,
+    fetch_duration: HistogramVec,
+    fetch_errors: IntCounterVec,
+    cache_requests: IntCounterVec,
+    feed_events: IntGaugeVec,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    data_age: GaugeVec,
+    /// When the events of each feed were last merged, for `data_age`.
+    refreshed: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let buckets = exponential_buckets(0.01, 2.5, 10).expect("valid buckets");
+
+        let metrics = Self {
+            registry: Registry::new_custom(Some("ical_merger".into()), None).expect("valid prefix"),
+            fetch_duration: HistogramVec::new(
+                HistogramOpts::new("fetch_duration_seconds", "Duration of upstream calendar requests.")
+                    .buckets(buckets.clone()),
+                &["source"],
+            )
+            .unwrap(),
+            fetch_errors: IntCounterVec::new(
+                Opts::new("fetch_errors_total", "Sources that could not be fetched or parsed."),
+                &["source", "kind"],
+            )
+            .unwrap(),
+            cache_requests: IntCounterVec::new(
+                Opts::new("cache_requests_total", "Cache lookups by cache and result (hit or miss)."),
+                &["cache", "result"],
+            )
+            .unwrap(),
+            feed_events: IntGaugeVec::new(
+                Opts::new("feed_events", "Components of a feed after merging and filtering."),
+                &["feed"],
+            )
+            .unwrap(),
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Requests served by route and status."),
+                &["route", "status"],
+            )
+            .unwrap(),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Duration of served requests.").buckets(buckets),
+                &["route", "status"],
+            )
+            .unwrap(),
+            data_age: GaugeVec::new(
+                Opts::new("feed_data_age_seconds", "Time since the events of a feed were last merged."),
+                &["feed"],
+            )
+            .unwrap(),
+            refreshed: Mutex::new(HashMap::new()),
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 7] = [
+            Box::new(metrics.fetch_duration.clone()),
+            Box::new(metrics.fetch_errors.clone()),
+            Box::new(metrics.cache_requests.clone()),
+            Box::new(metrics.feed_events.clone()),
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.request_duration.clone()),
+            Box::new(metrics.data_age.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("metric names are unique");
+        }
+
+        metrics
+    }
+
+    /// Sources are labelled by [`crate::lib::config::SourceConfig::label`],
+    /// never by their url.
+    pub fn observe_fetch(&self, source: &str, seconds: f64) {
+        self.fetch_duration.with_label_values(&[source]).observe(seconds);
+    }
+
+    pub fn count_fetch_error(&self, source: &str, error: &Error) {
+        self.fetch_errors.with_label_values(&[source, error.kind()]).inc();
+    }
+
+    pub fn count_cache(&self, cache: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_requests.with_label_values(&[cache, result]).inc();
+    }
+
+    /// Records a freshly merged feed.
+    pub fn feed_refreshed(&self, feed: &str, events: usize) {
+        self.feed_events.with_label_values(&[feed]).set(events as i64);
+        self.refreshed.lock().unwrap().insert(feed.to_string(), Utc::now());
+    }
+
+    /// Drops the series of feeds other than `feeds`, e.g. after a reload.
+    pub fn retain_feeds(&self, feeds: &[&str]) {
+        self.refreshed.lock().unwrap().retain(|feed, _| {
+            let keep = feeds.contains(&feed.as_str());
+            if !keep {
+                let _ = self.feed_events.remove_label_values(&[feed]);
+                let _ = self.data_age.remove_label_values(&[feed]);
+            }
+            keep
+        });
+    }
+
+    pub fn observe_request(&self, route: &str, status: u16, seconds: f64) {
+        let status = status.to_string();
+        self.requests.with_label_values(&[route, &status]).inc();
+        self.request_duration
+            .with_label_values(&[route, &status])
+            .observe(seconds);
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let now = Utc::now();
+        for (feed, refreshed) in self.refreshed.lock().unwrap().iter() {
+            let age = (now - *refreshed).num_milliseconds() as f64 / 1000.0;
+            self.data_age.with_label_values(&[feed]).set(age);
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics can be encoded");
+        String::from_utf8(buffer).expect("metrics are UTF-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renders_metrics() {
+        let metrics = Metrics::new();
+
+        metrics.observe_fetch("Family", 0.2);
+        metrics.count_fetch_error("Family", &Error::CircuitOpen);
+        metrics.count_cache("feed", true);
+        metrics.feed_refreshed("team", 12);
+        metrics.observe_request("/feeds/{name}", 200, 0.05);
+
+        let text = metrics.render();
+        assert!(text.contains(r#"ical_merger_fetch_errors_total{kind="circuit_open",source="Family"} 1"#));
+        assert!(text.contains(r#"ical_merger_cache_requests_total{cache="feed",result="hit"} 1"#));
+        assert!(text.contains(r#"ical_merger_feed_events{feed="team"} 12"#));
+        assert!(text.contains(r#"ical_merger_http_requests_total{route="/feeds/{name}",status="200"} 1"#));
+        assert!(text.contains("ical_merger_feed_data_age_seconds{feed=\"team\"}"));
+    }
+
+    #[test]
+    fn test_forgets_removed_feeds() {
+        let metrics = Metrics::new();
+        metrics.feed_refreshed("team", 12);
+        metrics.feed_refreshed("family", 3);
+        metrics.render();
+
+        metrics.retain_feeds(&["family"]);
+
+        let text = metrics.render();
+        assert!(!text.contains(r#"feed="team""#));
+        assert!(text.contains(r#"ical_merger_feed_events{feed="family"} 3"#));
+        assert!(text.contains(r#"ical_merger_feed_data_age_seconds{feed="family"}"#));
+    }
+}
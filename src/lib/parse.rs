@@ -0,0 +1,468 @@
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use icalendar::{parser::read_calendar, Calendar, CalendarComponent, Component};
+use serde::Serialize;
+
+use crate::lib::recurrence::event_duration;
+
+/// A problem found while reading a calendar that did not stop it from being used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParseWarning {
+    /// 1-based line in the decoded source, if the problem can be pinned to one.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ParseWarning {
+    fn at(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line: Some(line),
+            message: message.into(),
+        }
+    }
+
+    fn general(message: impl Into<String>) -> Self {
+        Self {
+            line: None,
+            message: message.into(),
+        }
+    }
+}
+
+/// Turns a response body into text, honouring the charset of the `Content-Type` header.
+///
+/// A byte order mark takes precedence over the header. Bodies without a charset that are
+/// not valid UTF-8 are assumed to be windows-1252, which is what most legacy feeds use.
+pub fn decode_body(body: &[u8], content_type: Option<&str>) -> (String, Vec<ParseWarning>) {
+    let mut warnings = Vec::new();
+
+    let label = content_type.and_then(charset_from_content_type);
+    let encoding = match label {
+        Some(label) => Encoding::for_label(label.as_bytes()).unwrap_or_else(|| {
+            warnings.push(ParseWarning::general(format!("unknown charset {label:?}, falling back to UTF-8")));
+            UTF_8
+        }),
+        None => UTF_8,
+    };
+
+    let (text, used_encoding, had_errors) = encoding.decode(body);
+    if !had_errors {
+        return (text.into_owned(), warnings);
+    }
+
+    if label.is_none() && used_encoding == UTF_8 {
+        warnings.push(ParseWarning::general("calendar is not valid UTF-8, decoded it as windows-1252"));
+        let (text, _) = WINDOWS_1252.decode_without_bom_handling(body);
+        return (text.into_owned(), warnings);
+    }
+
+    warnings.push(ParseWarning::general(format!(
+        "replaced invalid {} sequences",
+        used_encoding.name()
+    )));
+    (text.into_owned(), warnings)
+}
+
+fn charset_from_content_type(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// Warns about events whose DURATION is unreadable or too long, which are treated as
+/// having none.
+pub fn check_durations(calendar: &Calendar) -> Vec<ParseWarning> {
+    calendar
+        .components
+        .iter()
+        .filter_map(CalendarComponent::as_event)
+        .filter(|event| event.property_value("DURATION").is_some() && event_duration(event).is_none())
+        .map(|event| {
+            ParseWarning::general(format!(
+                "ignoring unreadable DURATION of event {}",
+                event.get_uid().unwrap_or("without UID")
+            ))
+        })
+        .collect()
+}
+
+/// Parses a calendar and fails on the first error, like `icalendar` itself does.
+///
+/// The error message points at the first unreadable content line, if there is one,
+/// by its line number in `text`.
+pub fn parse_strict(text: &str) -> std::result::Result<Calendar, String> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let text_unfolded = icalendar::parser::unfold(text);
+    let parsed_calender = read_calendar(&text_unfolded).map_err(|e| {
+        // The parser does not say where it failed, so look for the line ourselves.
+        let unreadable = logical_lines(text)
+            .into_iter()
+            .find(|(_, line)| begin_or_end(line).is_none() && !is_valid_property(line));
+        match unreadable {
+            Some((line_number, _)) => format!("line {line_number}: {e}"),
+            None => e,
+        }
+    })?;
+
+    Ok(Calendar::from(parsed_calender))
+}
+
+/// Parses a calendar component by component.
+///
+/// A component that cannot be read is dropped on its own instead of failing the whole
+/// calendar. Invalid property lines are removed first, so usually only the broken
+/// property is lost. Everything that had to be repaired is reported as a warning.
+pub fn parse_lenient(text: &str) -> (Calendar, Vec<ParseWarning>) {
+    let mut parser = LenientParser::default();
+
+    for (line_number, line) in logical_lines(text) {
+        parser.feed(line_number, &line);
+    }
+    parser.finish()
+}
+
+/// Unfolds the content lines, accepting CRLF, LF and CR line endings.
+///
+/// Every logical line keeps the number of the physical line it started on.
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut lines: Vec<(usize, String)> = Vec::new();
+
+    let normalized = text.replace("\r\n", "\n").replace('\r', "\n");
+    for (index, line) in normalized.split('\n').enumerate() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some((_, previous))) => previous.push_str(continuation),
+            _ if line.trim().is_empty() => {}
+            _ => lines.push((index + 1, line.to_string())),
+        }
+    }
+
+    lines
+}
+
+fn begin_or_end(line: &str) -> Option<(bool, String)> {
+    let (key, value) = line.split_once(':')?;
+    let name = value.trim().to_ascii_uppercase();
+
+    if key.eq_ignore_ascii_case("BEGIN") {
+        Some((true, name))
+    } else if key.eq_ignore_ascii_case("END") {
+        Some((false, name))
+    } else {
+        None
+    }
+}
+
+#[derive(Default)]
+struct LenientParser {
+    in_calendar: bool,
+    seen_calendar: bool,
+    properties: Vec<(usize, String)>,
+    block: Vec<(usize, String)>,
+    open: Vec<String>,
+    components: Vec<CalendarComponent>,
+    warnings: Vec<ParseWarning>,
+}
+
+impl LenientParser {
+    fn feed(&mut self, line_number: usize, line: &str) {
+        let marker = begin_or_end(line);
+
+        if !self.open.is_empty() {
+            self.feed_block(line_number, line, marker);
+            return;
+        }
+
+        match marker {
+            Some((true, name)) if name == "VCALENDAR" => {
+                if self.in_calendar {
+                    self.warnings
+                        .push(ParseWarning::at(line_number, "nested BEGIN:VCALENDAR, ignoring it"));
+                }
+                self.in_calendar = true;
+                self.seen_calendar = true;
+            }
+            Some((false, name)) if name == "VCALENDAR" => {
+                if !self.in_calendar {
+                    self.warnings
+                        .push(ParseWarning::at(line_number, "END:VCALENDAR without BEGIN:VCALENDAR"));
+                }
+                self.in_calendar = false;
+            }
+            Some((true, name)) => {
+                if !self.in_calendar {
+                    self.warnings
+                        .push(ParseWarning::at(line_number, format!("{name} outside of VCALENDAR")));
+                }
+                self.block.push((line_number, line.to_string()));
+                self.open.push(name);
+            }
+            Some((false, name)) => {
+                self.warnings
+                    .push(ParseWarning::at(line_number, format!("END:{name} without matching BEGIN")));
+            }
+            None if self.in_calendar => self.properties.push((line_number, line.to_string())),
+            None => self
+                .warnings
+                .push(ParseWarning::at(line_number, "ignoring content outside of VCALENDAR")),
+        }
+    }
+
+    fn feed_block(&mut self, line_number: usize, line: &str, marker: Option<(bool, String)>) {
+        match marker {
+            Some((true, name)) if self.open.contains(&name) => {
+                // A component can't contain itself, so the previous one was never closed.
+                self.warnings
+                    .push(ParseWarning::at(line_number, format!("missing END:{} before this line", self.open[0])));
+                self.close_block(self.open.len());
+                self.feed(line_number, line);
+            }
+            Some((true, name)) => {
+                self.block.push((line_number, line.to_string()));
+                self.open.push(name);
+            }
+            Some((false, name)) => match self.open.iter().rposition(|open| *open == name) {
+                Some(position) => {
+                    let missing = self.open.len() - position - 1;
+                    if missing > 0 {
+                        self.warnings.push(ParseWarning::at(
+                            line_number,
+                            format!("missing END:{} before this line", self.open[self.open.len() - 1]),
+                        ));
+                    }
+                    self.close_open(missing);
+                    self.block.push((line_number, line.to_string()));
+                    self.open.pop();
+
+                    if self.open.is_empty() {
+                        self.parse_block();
+                    }
+                }
+                None if name == "VCALENDAR" => {
+                    self.warnings
+                        .push(ParseWarning::at(line_number, format!("missing END:{}", self.open[0])));
+                    self.close_block(self.open.len());
+                    self.feed(line_number, line);
+                }
+                None => self
+                    .warnings
+                    .push(ParseWarning::at(line_number, format!("END:{name} without matching BEGIN"))),
+            },
+            None => self.block.push((line_number, line.to_string())),
+        }
+    }
+
+    /// Appends `END` lines for the innermost `count` open components.
+    fn close_open(&mut self, count: usize) {
+        for _ in 0..count {
+            let name = self.open.pop().expect("count never exceeds open components");
+            let line_number = self.block.last().map(|(n, _)| *n).unwrap_or_default();
+            self.block.push((line_number, format!("END:{name}")));
+        }
+    }
+
+    fn close_block(&mut self, count: usize) {
+        self.close_open(count);
+        self.parse_block();
+    }
+
+    fn parse_block(&mut self) {
+        let block = std::mem::take(&mut self.block);
+        let Some((start_line, begin)) = block.first().cloned() else {
+            return;
+        };
+
+        if let Ok(parsed) = read_calendar(&join_lines(&block)) {
+            self.components.extend(Calendar::from(parsed).components);
+            return;
+        }
+
+        // Drop the property lines the parser chokes on and try again.
+        let repaired = block
+            .into_iter()
+            .filter_map(|(line_number, line)| {
+                if begin_or_end(&line).is_some() || is_valid_property(&line) {
+                    return Some((line_number, line));
+                }
+
+                match without_parameters(&line) {
+                    Some(stripped) if is_valid_property(&stripped) => {
+                        self.warnings
+                            .push(ParseWarning::at(line_number, "dropped invalid property parameters"));
+                        Some((line_number, stripped))
+                    }
+                    _ => {
+                        self.warnings
+                            .push(ParseWarning::at(line_number, "dropped unreadable property"));
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        match read_calendar(&join_lines(&repaired)) {
+            Ok(parsed) => self.components.extend(Calendar::from(parsed).components),
+            Err(e) => self.warnings.push(ParseWarning::at(
+                start_line,
+                format!(
+                    "skipping {}: {}",
+                    begin.trim_start_matches("BEGIN:"),
+                    e.lines().next().unwrap_or_default()
+                ),
+            )),
+        }
+    }
+
+    fn finish(mut self) -> (Calendar, Vec<ParseWarning>) {
+        if !self.open.is_empty() {
+            let name = self.open[0].clone();
+            self.warnings
+                .push(ParseWarning::general(format!("missing END:{name} at end of file")));
+            self.close_block(self.open.len());
+        }
+
+        if self.in_calendar {
+            self.warnings
+                .push(ParseWarning::general("missing END:VCALENDAR at end of file"));
+        } else if !self.seen_calendar {
+            self.warnings
+                .push(ParseWarning::general("no BEGIN:VCALENDAR found"));
+        }
+
+        let mut calendar = Calendar::empty();
+        for (line_number, line) in self.properties {
+            match line.parse::<icalendar::Property>() {
+                Ok(property) if is_valid_property(&line) => {
+                    calendar.append_property(property);
+                }
+                _ => self.warnings.push(ParseWarning::at(
+                    line_number,
+                    "dropped unreadable calendar property",
+                )),
+            }
+        }
+        calendar.components = self.components;
+
+        (calendar, self.warnings)
+    }
+}
+
+fn join_lines(lines: &[(usize, String)]) -> String {
+    let mut joined = lines
+        .iter()
+        .map(|(_, line)| line.as_str())
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    joined.push_str("\r\n");
+    joined
+}
+
+/// `icalendar` happily parses a prefix of a broken line, so check the line as the only
+/// content of a component, which has to be consumed completely.
+fn is_valid_property(line: &str) -> bool {
+    read_calendar(&format!("BEGIN:X-PROBE\r\n{line}\r\nEND:X-PROBE\r\n")).is_ok()
+}
+
+fn without_parameters(line: &str) -> Option<String> {
+    let (head, value) = line.split_once(':')?;
+    let (name, _) = head.split_once(';')?;
+    Some(format!("{name}:{value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use icalendar::Component;
+
+    #[test]
+    fn test_warns_about_unreadable_durations() {
+        let text = "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:long\r\nDTSTART:20240101T100000Z\r\n\
+                    DURATION:P99999999D\r\nEND:VEVENT\r\nBEGIN:VEVENT\r\nUID:fine\r\n\
+                    DTSTART:20240101T100000Z\r\nDURATION:PT1H\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+        let (calendar, _) = parse_lenient(text);
+
+        assert_eq!(
+            check_durations(&calendar),
+            vec![ParseWarning::general("ignoring unreadable DURATION of event long")]
+        );
+    }
+
+    #[test]
+    fn test_strict_errors_name_the_unreadable_line() {
+        let text = "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:a\r\nSUMMARY:Fine\r\n\
+                    broken line\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+        let error = parse_strict(text).unwrap_err();
+
+        assert!(error.starts_with("line 5: "), "{error}");
+    }
+
+    #[test]
+    fn test_lenient_handles_bom_lf_and_missing_end() {
+        let text = "\u{feff}BEGIN:VCALENDAR\nVERSION:2.0\nBEGIN:VEVENT\nUID:a\nSUMMARY:Long\n  title\nEND:VEVENT\n";
+
+        let (calendar, warnings) = parse_lenient(text);
+
+        assert_eq!(calendar.components.len(), 1);
+        let event = calendar.components[0].as_event().unwrap();
+        assert_eq!(event.get_summary(), Some("Long title"));
+        assert_eq!(warnings, vec![ParseWarning::general("missing END:VCALENDAR at end of file")]);
+    }
+
+    #[test]
+    fn test_lenient_closes_unterminated_subcomponent() {
+        let text = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\nUID:good\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:broken\r\nBEGIN:VALARM\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:also-good\r\nEND:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+
+        let (calendar, warnings) = parse_lenient(text);
+
+        let uids = calendar
+            .components
+            .iter()
+            .filter_map(|c| c.as_event()?.get_uid())
+            .collect::<Vec<_>>();
+        assert_eq!(uids, vec!["good", "broken", "also-good"]);
+        assert_eq!(warnings, vec![ParseWarning::at(8, "missing END:VALARM before this line")]);
+    }
+
+    #[test]
+    fn test_lenient_drops_unreadable_lines() {
+        let text = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\nUID:a\r\nthis is not a property\r\nSUMMARY:Kept\r\nEND:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+
+        let (calendar, warnings) = parse_lenient(text);
+
+        let event = calendar.components[0].as_event().unwrap();
+        assert_eq!(event.get_summary(), Some("Kept"));
+        assert_eq!(warnings, vec![ParseWarning::at(4, "dropped unreadable property")]);
+    }
+
+    #[test]
+    fn test_lenient_starts_new_event_when_end_is_missing() {
+        let text = "BEGIN:VCALENDAR\nBEGIN:VEVENT\nUID:first\nBEGIN:VEVENT\nUID:second\nEND:VEVENT\nEND:VCALENDAR\n";
+
+        let (calendar, warnings) = parse_lenient(text);
+
+        assert_eq!(calendar.components.len(), 2);
+        assert_eq!(warnings, vec![ParseWarning::at(4, "missing END:VEVENT before this line")]);
+    }
+
+    #[test]
+    fn test_decode_body_uses_content_type_charset() {
+        let body = b"SUMMARY:Caf\xe9";
+
+        let (text, warnings) = decode_body(body, Some("text/calendar; charset=ISO-8859-1"));
+        assert_eq!(text, "SUMMARY:Café");
+        assert!(warnings.is_empty());
+
+        let (text, warnings) = decode_body(body, Some("text/calendar"));
+        assert_eq!(text, "SUMMARY:Café");
+        assert_eq!(warnings.len(), 1);
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::{HashSet, VecDeque};
+
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use icalendar::{CalendarDateTime, Component, DatePerhapsTime, Event, Property};
+
+use crate::lib::timezone::{local_to_utc, resolve_utc};
+
+/// Upper bound of recurrence periods looked at, so broken or endless rules
+/// cannot stall a refresh.
+const MAX_PERIODS: u32 = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// End of a recurrence as given by `UNTIL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    Date(NaiveDate),
+    Floating(NaiveDateTime),
+    Utc(DateTime<Utc>),
+}
+
+/// The parts of an RRULE that are supported. `BYHOUR`, `BYMINUTE`, `BYWEEKNO`,
+/// `BYYEARDAY` and sub-daily frequencies are not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RRule {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<Until>,
+    /// Weekdays with an optional ordinal, e.g. `-1FR` is the last Friday.
+    pub by_day: Vec<(Option<i32>, Weekday)>,
+    pub by_month_day: Vec<i32>,
+    pub by_month: Vec<u32>,
+    pub by_set_pos: Vec<i32>,
+}
+
+/// A single instance of an event, as absolute times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Occurrence {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub all_day: bool,
+}
+
+impl Occurrence {
+    /// Whether this occurrence takes up time within `[from, to)`. Instantaneous
+    /// events count if they happen inside it.
+    pub fn overlaps(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+        self.start < to && (self.end > from || self.start >= from)
+    }
+}
+
+pub fn parse_rrule(value: &str) -> Option<RRule> {
+    let mut rule = RRule {
+        freq: Frequency::Daily,
+        interval: 1,
+        count: None,
+        until: None,
+        by_day: Vec::new(),
+        by_month_day: Vec::new(),
+        by_month: Vec::new(),
+        by_set_pos: Vec::new(),
+    };
+    let mut freq = None;
+
+    for part in value.split(';') {
+        let (key, value) = part.split_once('=')?;
+        let list = || value.split(',').map(str::trim);
+
+        match key.trim().to_ascii_uppercase().as_str() {
+            "FREQ" => {
+                freq = Some(match value.trim().to_ascii_uppercase().as_str() {
+                    "DAILY" => Frequency::Daily,
+                    "WEEKLY" => Frequency::Weekly,
+                    "MONTHLY" => Frequency::Monthly,
+                    "YEARLY" => Frequency::Yearly,
+                    _ => return None,
+                })
+            }
+            "INTERVAL" => rule.interval = value.trim().parse().ok().filter(|&i| i > 0)?,
+            "COUNT" => rule.count = Some(value.trim().parse().ok()?),
+            "UNTIL" => rule.until = Some(parse_until(value.trim())?),
+            "BYDAY" => rule.by_day = list().map(parse_by_day).collect::<Option<_>>()?,
+            "BYMONTHDAY" => rule.by_month_day = list().map(|v| v.parse().ok()).collect::<Option<_>>()?,
+            "BYMONTH" => rule.by_month = list().map(|v| v.parse().ok()).collect::<Option<_>>()?,
+            "BYSETPOS" => rule.by_set_pos = list().map(|v| v.parse().ok()).collect::<Option<_>>()?,
+            _ => {}
+        }
+    }
+
+    rule.freq = freq?;
+    Some(rule)
+}
+
+fn parse_until(value: &str) -> Option<Until> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        let naive = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+        return Some(Until::Utc(naive.and_utc()));
+    }
+
+    NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .map(Until::Floating)
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y%m%d").map(Until::Date))
+        .ok()
+}
+
+fn parse_by_day(value: &str) -> Option<(Option<i32>, Weekday)> {
+    let split = value.len().checked_sub(2)?;
+    let (ordinal, day) = value.split_at(split);
+    let weekday = match day.to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    };
+    let ordinal = match ordinal.trim_start_matches('+') {
+        "" => None,
+        ordinal => Some(ordinal.parse().ok()?),
+    };
+
+    Some((ordinal, weekday))
+}
+
+/// Parses an RFC 5545 duration such as `PT1H30M`, `P1D` or `-P2W`.
+///
+/// Durations too long to represent are rejected like malformed ones.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (negative, value) = match value.as_bytes().first()? {
+        b'-' => (true, &value[1..]),
+        b'+' => (false, &value[1..]),
+        _ => (false, value),
+    };
+    let mut rest = value.strip_prefix('P')?;
+    let mut duration = Duration::zero();
+    let mut in_time = false;
+
+    while !rest.is_empty() {
+        if let Some(time) = rest.strip_prefix('T') {
+            in_time = true;
+            rest = time;
+            continue;
+        }
+
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let amount: i64 = rest[..digits].parse().ok()?;
+        let mut units = rest[digits..].chars();
+        let seconds = match (units.next()?, in_time) {
+            ('W', false) => 7 * 86_400,
+            ('D', false) => 86_400,
+            ('H', true) => 3_600,
+            ('M', true) => 60,
+            ('S', true) => 1,
+            _ => return None,
+        };
+        duration = duration.checked_add(&checked_seconds(amount, seconds)?)?;
+        rest = units.as_str();
+    }
+
+    Some(if negative { -duration } else { duration })
+}
+
+/// `amount` times `unit` seconds, or `None` if that does not fit a [`Duration`].
+pub fn checked_seconds(amount: i64, unit: i64) -> Option<Duration> {
+    amount
+        .checked_mul(unit)?
+        .checked_mul(1_000)
+        .map(Duration::milliseconds)
+}
+
+/// The DURATION of `event`, if it is readable and does not run past the end of
+/// time from DTSTART.
+pub fn event_duration(event: &Event) -> Option<Duration> {
+    let duration = parse_duration(event.property_value("DURATION")?)?;
+    let start = match event.get_start() {
+        Some(DatePerhapsTime::Date(date)) => date.and_time(NaiveTime::MIN),
+        Some(DatePerhapsTime::DateTime(CalendarDateTime::Floating(naive))) => naive,
+        Some(DatePerhapsTime::DateTime(CalendarDateTime::Utc(utc))) => utc.naive_utc(),
+        Some(DatePerhapsTime::DateTime(CalendarDateTime::WithTimezone { date_time, .. })) => date_time,
+        None => return Some(duration),
+    };
+
+    start.checked_add_signed(duration).map(|_| duration)
+}
+
+/// How a series is anchored in time.
+#[derive(Debug, Clone)]
+struct Anchor {
+    /// DTSTART as written, in its own timezone.
+    start: NaiveDateTime,
+    tz: Tz,
+    is_utc: bool,
+    all_day: bool,
+    /// Length of every instance. Whole days for all-day events, so they
+    /// follow midnight across DST changes.
+    length: Length,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Length {
+    Days(i64),
+    Exact(Duration),
+}
+
+impl Anchor {
+    fn of(event: &Event, fallback_tz: Tz) -> Option<Self> {
+        let (start, tz, is_utc, all_day) = match event.get_start()? {
+            DatePerhapsTime::Date(date) => (date.and_time(NaiveTime::MIN), fallback_tz, false, true),
+            DatePerhapsTime::DateTime(CalendarDateTime::Floating(naive)) => (naive, fallback_tz, false, false),
+            DatePerhapsTime::DateTime(CalendarDateTime::Utc(utc)) => (utc.naive_utc(), Tz::UTC, true, false),
+            DatePerhapsTime::DateTime(CalendarDateTime::WithTimezone { date_time, tzid }) => {
+                (date_time, tzid.parse().unwrap_or(fallback_tz), false, false)
+            }
+        };
+
+        let duration = event_duration(event);
+        let length = match (event.get_end(), duration, all_day) {
+            // Some producers write DTEND equal to DTSTART for single days.
+            (Some(DatePerhapsTime::Date(end)), _, true) => Length::Days((end - start.date()).num_days().max(1)),
+            (Some(end), _, _) => {
+                let start = if is_utc { start.and_utc() } else { local_to_utc(start, tz)? };
+                Length::Exact(resolve_utc(&end, fallback_tz)? - start)
+            }
+            (None, Some(duration), true) if duration.num_seconds() % 86_400 == 0 => Length::Days(duration.num_days()),
+            (None, Some(duration), _) => Length::Exact(duration),
+            // RFC 5545: a date-only event without an end lasts one day, others are instantaneous.
+            (None, None, true) => Length::Days(1),
+            (None, None, false) => Length::Exact(Duration::zero()),
+        };
+
+        Some(Anchor {
+            start,
+            tz,
+            is_utc,
+            all_day,
+            length,
+        })
+    }
+
+    fn to_utc(&self, naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+        if self.is_utc {
+            Some(naive.and_utc())
+        } else {
+            local_to_utc(naive, self.tz)
+        }
+    }
+
+    fn occurrence(&self, naive: NaiveDateTime) -> Option<Occurrence> {
+        let start = self.to_utc(naive)?;
+        let end = match self.length {
+            Length::Days(days) => self.to_utc(naive.checked_add_signed(checked_seconds(days, 86_400)?)?)?,
+            Length::Exact(duration) => start.checked_add_signed(duration)?,
+        };
+
+        Some(Occurrence {
+            start,
+            end: end.max(start),
+            all_day: self.all_day,
+        })
+    }
+}
+
+/// All occurrences of `event` in chronological order, honouring RRULE, RDATE and EXDATE.
+///
+/// Floating times and dates are interpreted in `fallback_tz`. An event without
+/// DTSTART has no occurrences. Endless series yield endless iterators, so
+/// callers have to stop on their own.
+pub fn occurrences(event: &Event, fallback_tz: Tz) -> Occurrences {
+    let anchor = Anchor::of(event, fallback_tz);
+    let rule = event.property_value("RRULE").and_then(parse_rrule);
+
+    let mut extra: Vec<NaiveDateTime> = Vec::new();
+    let mut excluded: HashSet<NaiveDateTime> = HashSet::new();
+    if let Some(anchor) = &anchor {
+        for property in date_list_properties(event, "RDATE") {
+            extra.extend(date_list(property, anchor));
+        }
+        for property in date_list_properties(event, "EXDATE") {
+            excluded.extend(date_list(property, anchor));
+        }
+    }
+    extra.sort();
+    extra.dedup();
+
+    Occurrences {
+        anchor,
+        rule,
+        period: 0,
+        emitted: 0,
+        pending: VecDeque::new(),
+        peeked: None,
+        extra: extra.into(),
+        excluded,
+        started: false,
+        exhausted: false,
+    }
+}
+
+/// Occurrences of `event` that overlap `[from, to)`.
+pub fn occurrences_between(event: &Event, fallback_tz: Tz, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Occurrence> {
+    occurrences(event, fallback_tz)
+        .take_while(|occurrence| occurrence.start < to)
+        .filter(|occurrence| occurrence.overlaps(from, to))
+        .collect()
+}
+
+fn date_list_properties<'a>(event: &'a Event, key: &'a str) -> impl Iterator<Item = &'a Property> {
+    event
+        .multi_properties()
+        .get(key)
+        .into_iter()
+        .flatten()
+        .chain(event.properties().get(key))
+}
+
+/// Values of an EXDATE or RDATE property, converted to the wall clock of the series.
+fn date_list(property: &Property, anchor: &Anchor) -> Vec<NaiveDateTime> {
+    let tz = property
+        .params()
+        .get("TZID")
+        .and_then(|tzid| tzid.value().parse::<Tz>().ok());
+
+    property
+        .value()
+        .split(',')
+        .filter_map(|value| {
+            let value = value.trim();
+            if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+                return Some(date.and_time(anchor.start.time()));
+            }
+
+            let instant = match value.strip_suffix('Z') {
+                Some(utc) => NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?.and_utc(),
+                None => {
+                    let naive = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+                    match tz {
+                        Some(tz) => local_to_utc(naive, tz)?,
+                        None if anchor.is_utc => naive.and_utc(),
+                        None => return Some(naive),
+                    }
+                }
+            };
+
+            Some(if anchor.is_utc {
+                instant.naive_utc()
+            } else {
+                instant.with_timezone(&anchor.tz).naive_local()
+            })
+        })
+        .collect()
+}
+
+pub struct Occurrences {
+    anchor: Option<Anchor>,
+    rule: Option<RRule>,
+    period: u32,
+    /// Rule instances returned so far, for COUNT. EXDATEs count as well.
+    emitted: u32,
+    /// Instances of the current period that are still to come, in order.
+    pending: VecDeque<NaiveDateTime>,
+    /// The next rule instance, once it has been looked at.
+    peeked: Option<NaiveDateTime>,
+    /// RDATEs not yet returned, in order.
+    extra: VecDeque<NaiveDateTime>,
+    excluded: HashSet<NaiveDateTime>,
+    started: bool,
+    exhausted: bool,
+}
+
+impl Occurrences {
+    /// The next instance generated by DTSTART and the RRULE, ignoring RDATE and EXDATE.
+    fn next_rule_instance(&mut self) -> Option<NaiveDateTime> {
+        let anchor = self.anchor.as_ref()?;
+
+        if !self.started {
+            self.started = true;
+            self.emitted = 1;
+            return Some(anchor.start);
+        }
+
+        let rule = self.rule.as_ref()?;
+        if self.exhausted || rule.count.is_some_and(|count| self.emitted >= count) {
+            return None;
+        }
+
+        while self.pending.is_empty() {
+            if self.period >= MAX_PERIODS {
+                self.exhausted = true;
+                return None;
+            }
+            self.period += 1;
+            let candidates = period_instances(rule, anchor.start, self.period - 1);
+            self.pending.extend(candidates.into_iter().filter(|c| *c > anchor.start));
+        }
+
+        let candidate = self.pending.pop_front()?;
+        if !within_until(rule, anchor, candidate) {
+            self.exhausted = true;
+            return None;
+        }
+
+        self.emitted += 1;
+        Some(candidate)
+    }
+
+    fn peek_rule_instance(&mut self) -> Option<NaiveDateTime> {
+        if self.peeked.is_none() {
+            self.peeked = self.next_rule_instance();
+        }
+        self.peeked
+    }
+}
+
+impl Iterator for Occurrences {
+    type Item = Occurrence;
+
+    fn next(&mut self) -> Option<Occurrence> {
+        loop {
+            let naive = match (self.peek_rule_instance(), self.extra.front().copied()) {
+                (Some(rule), Some(extra)) if extra < rule => self.extra.pop_front(),
+                (Some(rule), Some(extra)) => {
+                    if extra == rule {
+                        self.extra.pop_front();
+                    }
+                    self.peeked.take()
+                }
+                (Some(_), None) => self.peeked.take(),
+                (None, Some(_)) => self.extra.pop_front(),
+                (None, None) => return None,
+            }?;
+
+            if self.excluded.contains(&naive) {
+                continue;
+            }
+            if let Some(occurrence) = self.anchor.as_ref()?.occurrence(naive) {
+                return Some(occurrence);
+            }
+        }
+    }
+}
+
+fn within_until(rule: &RRule, anchor: &Anchor, candidate: NaiveDateTime) -> bool {
+    match rule.until {
+        None => true,
+        Some(Until::Date(date)) => candidate.date() <= date,
+        Some(Until::Floating(until)) => candidate <= until,
+        Some(Until::Utc(until)) => anchor.to_utc(candidate).is_none_or(|start| start <= until),
+    }
+}
+
+/// Instances of the `index`-th period of the rule, sorted.
+fn period_instances(rule: &RRule, start: NaiveDateTime, index: u32) -> Vec<NaiveDateTime> {
+    let step = index.saturating_mul(rule.interval);
+    let date = start.date();
+
+    let mut dates: Vec<NaiveDate> = match rule.freq {
+        Frequency::Daily => date
+            .checked_add_signed(Duration::days(step.into()))
+            .into_iter()
+            .filter(|day| {
+                (rule.by_day.is_empty() || rule.by_day.iter().any(|(_, wd)| *wd == day.weekday()))
+                    && (rule.by_month_day.is_empty() || month_days(rule, day.year(), day.month()).contains(day))
+            })
+            .collect(),
+        Frequency::Weekly => {
+            let week_start = date - Duration::days(date.weekday().num_days_from_monday().into());
+            let Some(week_start) = week_start.checked_add_signed(Duration::weeks(step.into())) else {
+                return Vec::new();
+            };
+            let weekdays: Vec<Weekday> = if rule.by_day.is_empty() {
+                vec![date.weekday()]
+            } else {
+                rule.by_day.iter().map(|(_, wd)| *wd).collect()
+            };
+            weekdays
+                .into_iter()
+                .map(|wd| week_start + Duration::days(wd.num_days_from_monday().into()))
+                .collect()
+        }
+        Frequency::Monthly => {
+            let Some(month) = date.with_day(1).and_then(|first| first.checked_add_months(Months::new(step))) else {
+                return Vec::new();
+            };
+            month_instances(rule, month.year(), month.month(), date.day())
+        }
+        Frequency::Yearly => {
+            let year = date.year() + step as i32;
+            if !rule.by_month.is_empty() || !rule.by_month_day.is_empty() {
+                let months: Vec<u32> = if rule.by_month.is_empty() {
+                    (1..=12).collect()
+                } else {
+                    rule.by_month.clone()
+                };
+                months
+                    .into_iter()
+                    .flat_map(|month| month_instances(rule, year, month, date.day()))
+                    .collect()
+            } else if !rule.by_day.is_empty() {
+                year_weekdays(rule, year)
+            } else {
+                NaiveDate::from_ymd_opt(year, date.month(), date.day()).into_iter().collect()
+            }
+        }
+    };
+
+    if !rule.by_month.is_empty() {
+        dates.retain(|day| rule.by_month.contains(&day.month()));
+    }
+    dates.sort();
+    dates.dedup();
+    let dates = apply_set_pos(&rule.by_set_pos, dates);
+
+    dates.into_iter().map(|day| day.and_time(start.time())).collect()
+}
+
+/// Dates of a month selected by BYDAY and BYMONTHDAY, or `default_day` without either.
+fn month_instances(rule: &RRule, year: i32, month: u32, default_day: u32) -> Vec<NaiveDate> {
+    match (rule.by_day.is_empty(), rule.by_month_day.is_empty()) {
+        (true, true) => NaiveDate::from_ymd_opt(year, month, default_day).into_iter().collect(),
+        (true, false) => month_days(rule, year, month),
+        (false, true) => month_weekdays(rule, year, month),
+        (false, false) => {
+            let days = month_days(rule, year, month);
+            month_weekdays(rule, year, month)
+                .into_iter()
+                .filter(|day| days.contains(day))
+                .collect()
+        }
+    }
+}
+
+fn month_days(rule: &RRule, year: i32, month: u32) -> Vec<NaiveDate> {
+    let length = days_in_month(year, month) as i32;
+    rule.by_month_day
+        .iter()
+        .filter_map(|&day| {
+            let day = if day < 0 { length + day + 1 } else { day };
+            NaiveDate::from_ymd_opt(year, month, u32::try_from(day).ok()?)
+        })
+        .collect()
+}
+
+fn month_weekdays(rule: &RRule, year: i32, month: u32) -> Vec<NaiveDate> {
+    let days = (1..=days_in_month(year, month)).filter_map(|day| NaiveDate::from_ymd_opt(year, month, day));
+    select_weekdays(&rule.by_day, days.collect())
+}
+
+fn year_weekdays(rule: &RRule, year: i32) -> Vec<NaiveDate> {
+    let days = NaiveDate::from_ymd_opt(year, 1, 1)
+        .into_iter()
+        .flat_map(|first| first.iter_days())
+        .take_while(|day| day.year() == year);
+    select_weekdays(&rule.by_day, days.collect())
+}
+
+/// Picks the days matching BYDAY from `days`, resolving ordinals like `2MO` or `-1FR` within them.
+fn select_weekdays(by_day: &[(Option<i32>, Weekday)], days: Vec<NaiveDate>) -> Vec<NaiveDate> {
+    by_day
+        .iter()
+        .flat_map(|&(ordinal, weekday)| {
+            let matching: Vec<NaiveDate> = days.iter().copied().filter(|day| day.weekday() == weekday).collect();
+            match ordinal {
+                None => matching,
+                Some(ordinal) => pick_position(&matching, ordinal).into_iter().collect(),
+            }
+        })
+        .collect()
+}
+
+fn apply_set_pos(by_set_pos: &[i32], dates: Vec<NaiveDate>) -> Vec<NaiveDate> {
+    if by_set_pos.is_empty() {
+        return dates;
+    }
+
+    let mut selected: Vec<NaiveDate> = by_set_pos
+        .iter()
+        .filter_map(|&position| pick_position(&dates, position))
+        .collect();
+    selected.sort();
+    selected.dedup();
+    selected
+}
+
+/// 1-based position, negative positions count from the end.
+fn pick_position(dates: &[NaiveDate], position: i32) -> Option<NaiveDate> {
+    let index = if position > 0 {
+        usize::try_from(position - 1).ok()?
+    } else {
+        dates.len().checked_sub(usize::try_from(-position).ok()?)?
+    };
+    dates.get(index).copied()
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|first| first.checked_add_months(Months::new(1)))
+        .and_then(|next| next.pred_opt())
+        .map(|last| last.day())
+        .unwrap_or(28)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use icalendar::EventLike;
+
+    fn event(lines: &str) -> Event {
+        let text = format!("BEGIN:VEVENT\r\nUID:test\r\n{}\r\nEND:VEVENT\r\n", lines.trim().replace('\n', "\r\n"));
+        match text.parse::<icalendar::CalendarComponent>().unwrap() {
+            icalendar::CalendarComponent::Event(event) => event,
+            _ => unreachable!(),
+        }
+    }
+
+    fn starts(event: &Event, limit: usize) -> Vec<String> {
+        occurrences(event, Tz::UTC)
+            .take(limit)
+            .map(|o| o.start.format("%Y-%m-%d %H:%M").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("PT1H30M"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("P1W"), Some(Duration::weeks(1)));
+        assert_eq!(parse_duration("-P1DT12H"), Some(-Duration::hours(36)));
+        assert_eq!(parse_duration("1H"), None);
+        assert_eq!(parse_duration("P99999999999999D"), None);
+        assert_eq!(parse_duration("P1é"), None);
+    }
+
+    #[test]
+    fn test_overlong_duration_is_ignored() {
+        let event = event("DTSTART:20240501T090000Z\nDURATION:P99999999D");
+
+        assert_eq!(event_duration(&event), None);
+        let occurrence = occurrences(&event, Tz::UTC).next().unwrap();
+        assert_eq!(occurrence.end, occurrence.start);
+    }
+
+    #[test]
+    fn test_monthly_last_weekday_with_count_and_exdate() {
+        let event = event(
+            "DTSTART:20240131T100000Z
+DTEND:20240131T110000Z
+RRULE:FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-1;COUNT=4
+EXDATE:20240329T100000Z",
+        );
+
+        assert_eq!(
+            starts(&event, 10),
+            vec!["2024-01-31 10:00", "2024-02-29 10:00", "2024-04-30 10:00"]
+        );
+    }
+
+    #[test]
+    fn test_weekly_follows_local_time_across_dst() {
+        let event = event(
+            "DTSTART;TZID=Europe/Berlin:20240321T090000
+DTEND;TZID=Europe/Berlin:20240321T100000
+RRULE:FREQ=WEEKLY;INTERVAL=1;UNTIL=20240404T070000Z
+RDATE;TZID=Europe/Berlin:20240323T120000",
+        );
+
+        // Berlin switches to summer time on 2024-03-31.
+        assert_eq!(
+            starts(&event, 10),
+            vec!["2024-03-21 08:00", "2024-03-23 11:00", "2024-03-28 08:00", "2024-04-04 07:00"]
+        );
+    }
+
+    #[test]
+    fn test_all_day_and_duration_lengths() {
+        let all_day = Event::new()
+            .all_day(NaiveDate::from_ymd_opt(2024, 5, 1).unwrap())
+            .done();
+        let with_duration = event("DTSTART:20240501T090000Z\nDURATION:PT45M");
+
+        let day = occurrences(&all_day, chrono_tz::Europe::Berlin).next().unwrap();
+        assert!(day.all_day);
+        assert_eq!(day.start, Utc.with_ymd_and_hms(2024, 4, 30, 22, 0, 0).unwrap());
+        assert_eq!(day.end, Utc.with_ymd_and_hms(2024, 5, 1, 22, 0, 0).unwrap());
+
+        let timed = occurrences(&with_duration, Tz::UTC).next().unwrap();
+        assert_eq!(timed.end - timed.start, Duration::minutes(45));
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use crate::lib::error::Result;
+use crate::lib::server::AppState;
+
+/// Reloads the config file whenever its content changes or the process gets
+/// a SIGHUP. Without a config file there is nothing to reload.
+pub async fn watch_config(state: AppState) {
+    let (path, every) = {
+        let live = state.live();
+        match &live.config.config_file {
+            Some(path) => (path.clone(), live.config.config_reload_interval_secs),
+            None => return,
+        }
+    };
+
+    let mut last = read(&path);
+    let mut ticker = interval(Duration::from_secs(every.max(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to install SIGHUP handler");
+
+    loop {
+        #[cfg(unix)]
+        let signalled = hangup.recv();
+        #[cfg(not(unix))]
+        let signalled = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = ticker.tick(), if every > 0 => {
+                let current = read(&path);
+                if current == last {
+                    continue;
+                }
+                last = current;
+            }
+            _ = signalled => {
+                last = read(&path);
+            }
+        }
+
+        match reload(&state) {
+            Ok(()) => tracing::info!(path = %path.display(), "reloaded config"),
+            Err(err) => tracing::error!(path = %path.display(), error = %err, "rejected config reload, keeping the current config"),
+        }
+    }
+}
+
+/// Re-reads the config file and switches to it if it is valid. Settings
+/// from the environment are kept as they are.
+pub fn reload(state: &AppState) -> Result<()> {
+    let config = state.live().config.clone().with_feeds()?;
+    state.replace_config(config);
+    Ok(())
+}
+
+fn read(path: &Path) -> Option<Vec<u8>> {
+    std::fs::read(path).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::config::{temp_config_file, Config};
+    use crate::lib::error::Error;
+
+    #[test]
+    fn test_rejects_invalid_config_and_keeps_the_current_one() {
+        let feed = |name: &str| format!("[[feeds]]\nname = \"{name}\"\nsources = [{{ url = \"https://example.com/{name}.ics\" }}]\n");
+        let path = temp_config_file(&feed("team"));
+        let state = AppState::new(Config::from_vars(&[("CONFIG_FILE", &path.display().to_string())]).unwrap()).unwrap();
+        let before = state.live();
+
+        std::fs::write(&path, feed("team/a")).unwrap();
+        assert!(matches!(reload(&state), Err(Error::InvalidConfig(_))));
+        assert!(std::sync::Arc::ptr_eq(&before, &state.live()));
+
+        std::fs::write(&path, feed("team") + &feed("family")).unwrap();
+        reload(&state).unwrap();
+        let names: Vec<_> = state.live().config.feeds.iter().map(|feed| feed.name.clone()).collect();
+        assert_eq!(names, vec!["team", "family"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
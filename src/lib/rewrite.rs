@@ -0,0 +1,172 @@
+use icalendar::{CalendarComponent, Component, Event, Property};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::lib::filter::{deserialize_regex, EventMatcher};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RewriteField {
+    Summary,
+    Description,
+    Location,
+}
+
+impl RewriteField {
+    fn property(self) -> &'static str {
+        match self {
+            RewriteField::Summary => "SUMMARY",
+            RewriteField::Description => "DESCRIPTION",
+            RewriteField::Location => "LOCATION",
+        }
+    }
+}
+
+/// Replaces every match of `pattern` in `field`. The replacement may refer to
+/// capture groups as `$1` or `${name}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    pub field: RewriteField,
+
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+
+    #[serde(default)]
+    pub replacement: String,
+
+    /// Only rewrite events matching these conditions.
+    #[serde(default)]
+    pub when: Option<EventMatcher>,
+}
+
+/// Applies `rules` in order to every event, so later rules see the output of
+/// earlier ones. A field rewritten to nothing is removed.
+pub fn apply_rewrites(components: Vec<CalendarComponent>, rules: &[RewriteRule]) -> Vec<CalendarComponent> {
+    if rules.is_empty() {
+        return components;
+    }
+
+    components
+        .into_iter()
+        .map(|component| match component {
+            CalendarComponent::Event(event) => CalendarComponent::Event(rewrite_event(event, rules)),
+            other => other,
+        })
+        .collect()
+}
+
+pub fn rewrite_event(mut event: Event, rules: &[RewriteRule]) -> Event {
+    for rule in rules {
+        if rule.when.as_ref().is_some_and(|when| !when.matches(&event)) {
+            continue;
+        }
+
+        let key = rule.field.property();
+        let Some(property) = event.properties().get(key) else {
+            continue;
+        };
+
+        let rewritten = rule.pattern.replace_all(property.value(), rule.replacement.as_str()).trim().to_string();
+        if rewritten.is_empty() {
+            event.remove_property(key);
+        } else {
+            // Keep parameters like LANGUAGE and ALTREP.
+            let mut updated = Property::new(key, rewritten);
+            for parameter in property.params().values() {
+                updated.append_parameter(parameter.clone());
+            }
+            event.append_property(updated);
+        }
+    }
+
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::filter::parse_rules;
+    use icalendar::EventLike;
+
+    fn sample(summary: &str, description: &str, location: &str) -> Event {
+        let text = format!(
+            "BEGIN:VEVENT\r\nUID:sample\r\nDTSTART:20240101T100000Z\r\nDTEND:20240101T110000Z\r\n\
+             SUMMARY:{summary}\r\nDESCRIPTION:{description}\r\nLOCATION:{location}\r\nEND:VEVENT\r\n"
+        );
+        match text.parse::<CalendarComponent>().unwrap() {
+            CalendarComponent::Event(event) => event,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_strips_zoom_links_and_normalizes_rooms() {
+        let rules = parse_rules(
+            r#"
+            [[rewrites]]
+            field = "description"
+            pattern = 'https://\S*zoom\.us/\S+'
+
+            [[rewrites]]
+            field = "location"
+            pattern = '(?i)^room\s*(\d+).*$'
+            replacement = "Room $1"
+            "#,
+        );
+        let event = sample("Sync", "Join https://acme.zoom.us/j/123?pwd=secret", "ROOM 4 (2nd floor)");
+
+        let event = rewrite_event(event, &rules);
+
+        assert_eq!(event.get_description(), Some("Join"));
+        assert_eq!(event.get_location(), Some("Room 4"));
+    }
+
+    #[test]
+    fn test_conditions_and_empty_results() {
+        let rules = parse_rules(
+            r#"
+            [[rewrites]]
+            field = "summary"
+            pattern = '^Meeting with .*$'
+            replacement = "Meeting"
+            when = { location = "Office" }
+
+            [[rewrites]]
+            field = "description"
+            pattern = '.*'
+            "#,
+        );
+
+        let in_office = rewrite_event(sample("Meeting with Alice", "Agenda", "Office"), &rules);
+        let remote = rewrite_event(sample("Meeting with Bob", "Agenda", "Home"), &rules);
+
+        assert_eq!(in_office.get_summary(), Some("Meeting"));
+        assert_eq!(remote.get_summary(), Some("Meeting with Bob"));
+        assert_eq!(in_office.get_description(), None);
+    }
+
+    #[test]
+    fn test_keeps_property_parameters() {
+        let rules = parse_rules(
+            r#"
+            [[rewrites]]
+            field = "location"
+            pattern = 'Raum'
+            replacement = "Room"
+            "#,
+        );
+        let text = "BEGIN:VEVENT\r\nUID:sample\r\nDTSTART:20240101T100000Z\r\n\
+                    LOCATION;LANGUAGE=de;ALTREP=\"https://example.com/map\":Raum 4\r\nEND:VEVENT\r\n";
+        let CalendarComponent::Event(event) = text.parse::<CalendarComponent>().unwrap() else {
+            unreachable!()
+        };
+
+        let event = rewrite_event(event, &rules);
+
+        let location = &event.properties()["LOCATION"];
+        assert_eq!(location.value(), "Room 4");
+        assert_eq!(location.params()["LANGUAGE"].value(), "de");
+        assert_eq!(location.params()["ALTREP"].value(), "https://example.com/map");
+    }
+}
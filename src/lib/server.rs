@@ -1,24 +1,192 @@
-use axum::extract::State;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+
+use axum::extract::{MatchedPath, Path, Query, Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use axum::routing::get;
 use axum::Router;
-use cached::proc_macro::once;
-use tokio::{signal, time::Duration};
+use cached::{Cached, TimedCache};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use futures::future::join_all;
+use icalendar::Calendar;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use uuid::Uuid;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::{signal, time::{Duration, Instant}};
 
 use crate::lib::{
-    calendar::{filter_future_days, hide_details, urls_to_merged_calendar},
-    config::Config,
+    calendar::{feed_busy_slots, feed_working_periods, HOLIDAY_SOURCE, finish_feed, freebusy_feed, group_events, merged_events, set_prodid, FeedEvents},
+    config::{Config, FeedConfig},
     error::{Error, Result},
+    fetch::{Fetcher, SourceStatus},
+    free::{free_slots, parse_duration_param, parse_time_param, slots_to_events, slots_to_json, vfreebusy},
+    group::{group_free_calendar, group_free_periods, heatmap, people},
+    metrics::Metrics,
+    parse::ParseWarning,
+    reload::watch_config,
+    stats::{feed_stats, Stats},
 };
 
+const REQUEST_ID: &str = "x-request-id";
+
+#[derive(Clone)]
+pub struct AppState {
+    pub fetcher: Arc<Fetcher>,
+    pub metrics: Arc<Metrics>,
+    /// Replaced as a whole when the config file is reloaded.
+    live: Arc<RwLock<Arc<LiveConfig>>>,
+}
+
+type BuildLock = Arc<AsyncMutex<()>>;
+
+/// A configuration together with the caches built from it, so a request never
+/// mixes feeds of an old and a new configuration.
+pub struct LiveConfig {
+    pub config: Config,
+    /// Rendered feeds by name, kept for the refresh interval.
+    pub cache: Mutex<TimedCache<String, String>>,
+    /// Merged and filtered events by feed name, shared by all endpoints of a feed.
+    pub events: Mutex<TimedCache<String, FeedEvents>>,
+    /// Like `events`, but merged without deduplication for group availability.
+    pub group_events: Mutex<TimedCache<String, FeedEvents>>,
+    /// Held while a cache entry is built, by cache and feed name.
+    builds: Mutex<HashMap<(&'static str, String), BuildLock>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        let lifespan = Duration::from_secs(config.refresh_interval_secs);
+        Self {
+            cache: Mutex::new(TimedCache::with_lifespan(lifespan)),
+            events: Mutex::new(TimedCache::with_lifespan(lifespan)),
+            group_events: Mutex::new(TimedCache::with_lifespan(lifespan)),
+            builds: Mutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    /// A live config for `config` that takes over the cached entries of the feeds
+    /// whose definition did not change.
+    fn reloaded(&self, config: Config) -> Self {
+        let live = LiveConfig::new(config);
+        let changed: Vec<&str> = self
+            .config
+            .feeds
+            .iter()
+            .filter(|old| live.config.feed(&old.name).is_none_or(|new| !new.same_definition(old)))
+            .map(|feed| feed.name.as_str())
+            .collect();
+
+        take_over(&self.cache, &live.cache, &changed);
+        take_over(&self.events, &live.events, &changed);
+        take_over(&self.group_events, &live.group_events, &changed);
+        live
+    }
+
+    fn build_lock(&self, kind: &'static str, name: &str) -> BuildLock {
+        self.builds
+            .lock()
+            .unwrap()
+            .entry((kind, name.to_string()))
+            .or_default()
+            .clone()
+    }
+
+    fn feed(&self, name: &str) -> Result<&FeedConfig> {
+        self.config
+            .feed(name)
+            .ok_or_else(|| Error::FeedNotFound(name.to_string()))
+    }
+}
+
+/// Moves the entries of `old` to `new`, except those of the `changed` feeds.
+fn take_over<V>(old: &Mutex<TimedCache<String, V>>, new: &Mutex<TimedCache<String, V>>, changed: &[&str]) {
+    let mut new = new.lock().unwrap();
+    std::mem::swap(&mut *old.lock().unwrap(), &mut *new);
+    for name in changed {
+        new.cache_remove(*name);
+    }
+}
+
+impl AppState {
+    pub fn new(config: Config) -> Result<Self> {
+        let metrics = Arc::new(Metrics::new());
+        Ok(Self {
+            fetcher: Arc::new(Fetcher::with_metrics(&config, metrics.clone())?),
+            metrics,
+            live: Arc::new(RwLock::new(Arc::new(LiveConfig::new(config)))),
+        })
+    }
+
+    /// The current configuration. Requests keep using the one they started
+    /// with even if it is replaced meanwhile.
+    pub fn live(&self) -> Arc<LiveConfig> {
+        self.live.read().unwrap().clone()
+    }
+
+    /// Switches to `config`. Feeds that did not change keep their caches, the
+    /// others are rebuilt from the upstream responses cached in the [`Fetcher`].
+    /// Metrics of feeds that no longer exist are dropped.
+    pub fn replace_config(&self, config: Config) {
+        let names: Vec<&str> = config.feeds.iter().map(|feed| feed.name.as_str()).collect();
+        self.metrics.retain_feeds(&names);
+        let mut live = self.live.write().unwrap();
+        *live = Arc::new(live.reloaded(config));
+    }
+}
+
 pub async fn start_server(config: Config) -> Result<()> {
-    let app = Router::new()
-        .route("/", get(handler))
-        .with_state(config.clone());
+    config.validate()?;
+
+    let state = AppState::new(config.clone())?;
+    tokio::spawn(watch_config(state.clone()));
+    tokio::spawn(warm_up(state.clone()));
+
+    let mut app = Router::new()
+        .route("/", get(default_feed))
+        .route("/feeds/{name}", get(feed))
+        .route("/feeds/{name}/free", get(free))
+        .route("/feeds/{name}/group", get(group))
+        .route("/feeds/{name}/stats", get(stats))
+        .route("/diagnostics", get(diagnostics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status));
+
+    if config.metrics_enabled {
+        match &config.metrics_address {
+            Some(address) => {
+                let metrics_app = Router::new().route("/metrics", get(metrics)).with_state(state.clone());
+                let listener = tokio::net::TcpListener::bind(address).await?;
+                tracing::info!(%address, "serving metrics");
+                tokio::spawn(async move {
+                    if let Err(err) = axum::serve(listener, metrics_app)
+                        .with_graceful_shutdown(shutdown_signal())
+                        .await
+                    {
+                        tracing::error!(error = %err, "metrics server stopped");
+                    }
+                });
+            }
+            None => app = app.route("/metrics", get(metrics)),
+        }
+    }
+
+    let app = app
+        .route_layer(middleware::from_fn_with_state(state.clone(), track_request))
+        .layer(middleware::from_fn(request_span))
+        .with_state(state);
 
     let listener =
         tokio::net::TcpListener::bind(format!("{}:{}", &config.host, &config.port)).await?;
 
-    println!("Started listening on {}:{}", &config.host, &config.port);
+    tracing::info!(host = %config.host, port = config.port, feeds = config.feeds.len(), "started listening");
 
     axum::serve(listener, app)
         .with_graceful_shutdown(shutdown_signal())
@@ -26,21 +194,372 @@ pub async fn start_server(config: Config) -> Result<()> {
         .map_err(Error::IO)
 }
 
-#[once(time = 900, result = true, sync_writes = true)]
-async fn handler(State(config): State<Config>) -> Result<String> {
-    // cached_calendar(config.urls).await
-    let mut c = urls_to_merged_calendar(config.urls, &config.tz_offsets).await?;
+/// Runs every request in a span with a request id, taken from the
+/// `x-request-id` header if the client sent one and returned in it.
+async fn request_span(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let span = tracing::info_span!("request", %id, method = %request.method(), path = request.uri().path());
+    let started = Instant::now();
+
+    let mut response = next.run(request).instrument(span.clone()).await;
+
+    span.in_scope(|| {
+        tracing::info!(
+            status = response.status().as_u16(),
+            millis = started.elapsed().as_millis() as u64,
+            "finished request"
+        )
+    });
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// Counts requests and their duration per route for `/metrics`.
+///
+/// Added as a route layer, so it only runs for requests that matched a route.
+async fn track_request(State(state): State<AppState>, route: MatchedPath, request: Request, next: Next) -> Response {
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    state
+        .metrics
+        .observe_request(route.as_str(), response.status().as_u16(), started.elapsed().as_secs_f64());
+    response
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct FeedQuery {
+    #[serde(default)]
+    format: FeedFormat,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum FeedFormat {
+    #[default]
+    Ics,
+    /// Only the busy time, as a single VFREEBUSY.
+    Freebusy,
+}
+
+/// Serves the first configured feed, which is the only one without a config file.
+async fn default_feed(State(state): State<AppState>, Query(query): Query<FeedQuery>) -> Result<String> {
+    let name = state.live().config.feeds[0].name.clone();
+    serve_feed(&state, &name, query.format).await
+}
+
+async fn feed(State(state): State<AppState>, Path(name): Path<String>, Query(query): Query<FeedQuery>) -> Result<String> {
+    serve_feed(&state, &name, query.format).await
+}
+
+async fn serve_feed(state: &AppState, name: &str, format: FeedFormat) -> Result<String> {
+    let live = state.live();
+    match format {
+        FeedFormat::Ics => render_feed(state, &live, name).await,
+        FeedFormat::Freebusy => {
+            let feed = live.feed(name)?;
+            let events = feed_events(state, &live, feed).await?;
+            Ok(freebusy_feed(events, &live.config, feed, feed.window(Utc::now())).to_string())
+        }
+    }
+}
+
+async fn render_feed(state: &AppState, live: &LiveConfig, name: &str) -> Result<String> {
+    cached(state, live, &live.cache, "feed", name, async {
+        let feed = live.feed(name)?;
+        let events = feed_events(state, live, feed).await?;
+        Ok(finish_feed(events, &live.config, feed, feed.window(Utc::now())).to_string())
+    })
+    .await
+}
+
+/// Builds every feed once at startup, so `/readyz` does not wait for the
+/// first request.
+async fn warm_up(state: AppState) {
+    let live = state.live();
+    let builds = live.config.feeds.iter().map(|feed| async {
+        if let Err(err) = render_feed(&state, &live, &feed.name).await {
+            tracing::warn!(feed = %feed.name, error = %err, "cannot build feed");
+        }
+    });
+    join_all(builds).await;
+}
+
+async fn feed_events(state: &AppState, live: &LiveConfig, feed: &FeedConfig) -> Result<FeedEvents> {
+    cached(state, live, &live.events, "events", &feed.name, async {
+        let events = merged_events(&state.fetcher, &live.config, feed).await?;
+        state.metrics.feed_refreshed(&feed.name, events.components.len());
+        Ok(events)
+    })
+    .await
+}
+
+async fn feed_group_events(state: &AppState, live: &LiveConfig, feed: &FeedConfig) -> Result<FeedEvents> {
+    let build = group_events(&state.fetcher, &live.config, feed);
+    cached(state, live, &live.group_events, "group_events", &feed.name, build).await
+}
+
+/// Looks up `name` in `cache` or runs `build`. Only one build per cache and
+/// feed runs at a time, concurrent misses wait for it and use its result.
+async fn cached<V: Clone>(
+    state: &AppState,
+    live: &LiveConfig,
+    cache: &Mutex<TimedCache<String, V>>,
+    kind: &'static str,
+    name: &str,
+    build: impl Future<Output = Result<V>>,
+) -> Result<V> {
+    let lookup = || cache.lock().unwrap().cache_get(name).cloned();
+    if let Some(value) = lookup() {
+        state.metrics.count_cache(kind, true);
+        return Ok(value);
+    }
+
+    let lock = live.build_lock(kind, name);
+    let _building = lock.lock().await;
+
+    // Another request may have finished the build while this one waited.
+    let value = lookup();
+    state.metrics.count_cache(kind, value.is_some());
+    if let Some(value) = value {
+        return Ok(value);
+    }
+
+    let value = build.await?;
+    cache.lock().unwrap().cache_set(name.to_string(), value.clone());
+    Ok(value)
+}
+
+/// Longest range that can be asked for at once, since every recurring event is
+/// expanded over all of it.
+const MAX_RANGE_DAYS: i64 = 90;
 
-    if let Some(days_limit) = config.future_days_limit {
-        c = filter_future_days(c, days_limit);
+/// Parses the `from` and `to` query parameters, by default now and a week later.
+fn time_range(from: Option<&str>, to: Option<&str>, tz: Tz) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let parse = |value: Option<&str>, name: &str| -> Result<Option<DateTime<Utc>>> {
+        value
+            .map(|value| parse_time_param(value, tz).ok_or_else(|| Error::BadRequest(format!("invalid {name}: {value}"))))
+            .transpose()
+    };
+
+    let from = parse(from, "from")?.unwrap_or_else(Utc::now);
+    let to = parse(to, "to")?.unwrap_or(from + chrono::Duration::days(7));
+    if to <= from {
+        return Err(Error::BadRequest("to has to be after from".into()));
+    }
+    if to - from > chrono::Duration::days(MAX_RANGE_DAYS) {
+        return Err(Error::BadRequest(format!("the range can be at most {MAX_RANGE_DAYS} days long")));
+    }
+    Ok((from, to))
+}
+
+fn duration_param(value: Option<&str>, name: &str) -> Result<Option<chrono::Duration>> {
+    value
+        .map(|value| parse_duration_param(value).ok_or_else(|| Error::BadRequest(format!("invalid {name}: {value}"))))
+        .transpose()
+}
+
+#[derive(Deserialize, Debug)]
+struct FreeQuery {
+    from: Option<String>,
+    to: Option<String>,
+    duration: Option<String>,
+    #[serde(default)]
+    format: FreeFormat,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum FreeFormat {
+    #[default]
+    Json,
+    /// One VEVENT per free slot.
+    Ics,
+    /// A single VFREEBUSY listing the free slots.
+    Freebusy,
+}
+
+/// Free slots of a feed within its working hours, by default for the next week.
+async fn free(State(state): State<AppState>, Path(name): Path<String>, Query(query): Query<FreeQuery>) -> Result<Response> {
+    let live = state.live();
+    let feed = live.feed(&name)?;
+    let tz = feed.timezone();
+
+    let (from, to) = time_range(query.from.as_deref(), query.to.as_deref(), tz)?;
+    let duration = duration_param(query.duration.as_deref(), "duration")?.unwrap_or_else(chrono::Duration::zero);
+
+    let events = feed_events(&state, &live, feed).await?;
+    let busy = feed_busy_slots(&events.components, &events.holidays, feed, from, to);
+    let slots = free_slots(&busy, &feed_working_periods(feed, from, to), tz, &feed.free, duration);
+
+    let mut calendar = match query.format {
+        FreeFormat::Json => return Ok(Json(slots_to_json(&slots, tz)).into_response()),
+        FreeFormat::Ics => slots_to_events(&slots),
+        FreeFormat::Freebusy => {
+            let periods: Vec<_> = slots.iter().map(|slot| (slot.start, slot.end, "FREE")).collect();
+            Calendar::from([vfreebusy(from, to, &periods)])
+        }
+    };
+
+    set_prodid(&mut calendar);
+    Ok(calendar.to_string().into_response())
+}
+
+#[derive(Deserialize, Debug)]
+struct GroupQuery {
+    from: Option<String>,
+    to: Option<String>,
+    /// Length of a heatmap row.
+    step: Option<String>,
+    /// Only for `ics`: show times when at least this many people are free
+    /// instead of everyone.
+    min_free: Option<usize>,
+    #[serde(default)]
+    format: GroupFormat,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum GroupFormat {
+    /// How many and which people are busy, per step.
+    #[default]
+    Json,
+    /// The times when enough people are free, as events.
+    Ics,
+}
+
+/// Shortest heatmap row, which together with [`MAX_RANGE_DAYS`] bounds the
+/// size of the heatmap.
+const MIN_GROUP_STEP_MINS: i64 = 5;
+
+/// Availability of a feed whose sources are people.
+async fn group(State(state): State<AppState>, Path(name): Path<String>, Query(query): Query<GroupQuery>) -> Result<Response> {
+    let live = state.live();
+    let feed = live.feed(&name)?;
+    if !feed.group {
+        return Err(Error::BadRequest(format!("feed {name} is not a group, see `group = true`")));
     }
+    let tz = feed.timezone();
+    let (from, to) = time_range(query.from.as_deref(), query.to.as_deref(), tz)?;
+    let step = duration_param(query.step.as_deref(), "step")?.unwrap_or_else(|| chrono::Duration::minutes(30));
+    if step < chrono::Duration::minutes(MIN_GROUP_STEP_MINS) {
+        return Err(Error::BadRequest(format!("step has to be at least {MIN_GROUP_STEP_MINS} minutes")));
+    }
+
+    let events = feed_group_events(&state, &live, feed).await?;
+    let people = people(&events, feed, from, to);
 
-    match config.hide_details {
-        true => Ok(hide_details(c).to_string()),
-        false => Ok(c.to_string()),
+    match query.format {
+        GroupFormat::Json => Ok(Json(heatmap(&people, from, to, step, tz)).into_response()),
+        GroupFormat::Ics => {
+            let periods = group_free_periods(&people, from, to, query.min_free.unwrap_or(people.len()));
+            let mut calendar = group_free_calendar(&periods, people.len());
+            set_prodid(&mut calendar);
+            Ok(calendar.to_string().into_response())
+        }
     }
 }
 
+#[derive(Deserialize, Debug)]
+struct StatsQuery {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// Meeting load of a feed, by default for the next week.
+async fn stats(State(state): State<AppState>, Path(name): Path<String>, Query(query): Query<StatsQuery>) -> Result<Json<Stats>> {
+    let live = state.live();
+    let feed = live.feed(&name)?;
+    let (from, to) = time_range(query.from.as_deref(), query.to.as_deref(), feed.timezone())?;
+
+    let events = feed_events(&state, &live, feed).await?;
+    Ok(Json(feed_stats(&events, feed, from, to)))
+}
+
+/// A source in `/status`, named by its [label](crate::lib::config::SourceConfig::label).
+#[derive(Serialize, Debug)]
+struct SourceReport {
+    source: String,
+    #[serde(flatten)]
+    status: SourceStatus,
+}
+
+/// Parse warnings of a source in `/diagnostics`.
+#[derive(Serialize, Debug)]
+struct SourceWarnings {
+    source: String,
+    parse_warnings: Vec<ParseWarning>,
+}
+
+/// The label and url of every source of `feed`, in the configured order and
+/// followed by its holiday source.
+fn labelled_sources(feed: &FeedConfig) -> Vec<(String, &str)> {
+    let holidays = feed.availability.as_ref().and_then(|availability| availability.holiday_source.as_deref());
+
+    feed.sources
+        .iter()
+        .map(|source| (source.label(), source.url.as_str()))
+        .chain(holidays.map(|url| (HOLIDAY_SOURCE.to_string(), url)))
+        .collect()
+}
+
+async fn diagnostics(State(state): State<AppState>) -> Json<BTreeMap<String, Vec<SourceWarnings>>> {
+    let live = state.live();
+    let feeds = live.config.feeds.iter().map(|feed| {
+        let sources = labelled_sources(feed)
+            .into_iter()
+            .map(|(source, url)| SourceWarnings {
+                source,
+                parse_warnings: state.fetcher.source_status(url).parse_warnings,
+            })
+            .collect();
+        (feed.name.clone(), sources)
+    });
+    Json(feeds.collect())
+}
+
+/// The process is up and serving requests.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// At least one source has been read, so there is something to serve.
+async fn readyz(State(state): State<AppState>) -> (StatusCode, &'static str) {
+    if state.fetcher.has_succeeded() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// The latest refresh of every source, by feed.
+async fn status(State(state): State<AppState>) -> Json<BTreeMap<String, Vec<SourceReport>>> {
+    let live = state.live();
+    let feeds = live.config.feeds.iter().map(|feed| {
+        let sources = labelled_sources(feed)
+            .into_iter()
+            .map(|(source, url)| SourceReport {
+                source,
+                status: state.fetcher.source_status(url),
+            })
+            .collect();
+        (feed.name.clone(), sources)
+    });
+    Json(feeds.collect())
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], state.metrics.render())
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -64,3 +583,83 @@ async fn shutdown_signal() {
         _ = terminate => {},
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::config::temp_config_file;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_concurrent_misses_build_once() {
+        let state = AppState::new(Config::from_vars(&[("URLS", "https://example.com/a.ics")]).unwrap()).unwrap();
+        let live = state.live();
+        let builds = AtomicUsize::new(0);
+
+        let build = || async {
+            builds.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok("calendar".to_string())
+        };
+        let (first, second) = tokio::join!(
+            cached(&state, &live, &live.cache, "feed", "default", build()),
+            cached(&state, &live, &live.cache, "feed", "default", build()),
+        );
+
+        assert_eq!((first.unwrap(), second.unwrap()), ("calendar".to_string(), "calendar".to_string()));
+        assert_eq!(builds.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_the_caches_of_unchanged_feeds() {
+        // Nothing listens on the discard port, so a fetch would fail.
+        let feed = |name: &str, extra: &str| {
+            format!("[[feeds]]\nname = \"{name}\"\n{extra}sources = [{{ url = \"http://127.0.0.1:9/{name}.ics\" }}]\n")
+        };
+        let path = temp_config_file(&(feed("team", "") + &feed("family", "")));
+        let state = AppState::new(Config::from_vars(&[("CONFIG_FILE", &path.display().to_string())]).unwrap()).unwrap();
+        for name in ["team", "family"] {
+            state.live().cache.lock().unwrap().cache_set(name.to_string(), format!("cached {name}"));
+        }
+
+        std::fs::write(&path, feed("team", "") + &feed("family", "hide_details = false\n")).unwrap();
+        crate::lib::reload::reload(&state).unwrap();
+
+        let live = state.live();
+        assert_eq!(render_feed(&state, &live, "team").await.unwrap(), "cached team");
+        assert!(live.cache.lock().unwrap().cache_get("family").is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_time_range_is_capped() {
+        let tz = chrono_tz::UTC;
+
+        assert!(time_range(Some("2024-01-01"), Some("2024-03-31"), tz).is_ok());
+        assert!(matches!(
+            time_range(Some("2024-01-01"), Some("2024-04-01"), tz),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            time_range(Some("2024-01-02"), Some("2024-01-01"), tz),
+            Err(Error::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_status_names_sources_without_their_url() {
+        let url = "https://calendar.google.com/calendar/ical/me/private-abc123/basic.ics";
+        let state = AppState::new(Config::from_vars(&[("URLS", url)]).unwrap()).unwrap();
+        state
+            .fetcher
+            .record_source_error("calendar.google.com", url, None, &Error::ResponseTooLarge(10));
+
+        let Json(status) = status(State(state)).await;
+
+        let json = serde_json::to_string(&status).unwrap();
+        assert_eq!(status["default"][0].source, "calendar.google.com");
+        assert_eq!(status["default"][0].status.last_error.as_deref(), Some("response exceeds the limit of 10 bytes"));
+        assert!(!json.contains("private-abc123"), "{json}");
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc};
+use chrono_tz::Tz;
+use icalendar::Component;
+use serde::Serialize;
+
+use crate::lib::busy::{busy_events, BusySlot};
+use crate::lib::calendar::{feed_busy_slots, FeedEvents, SOURCE_PROPERTY};
+use crate::lib::config::FeedConfig;
+use crate::lib::filter::categories;
+use crate::lib::recurrence::occurrences_between;
+use crate::lib::timezone::local_to_utc;
+
+/// Meeting load of a feed within a time range.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub from: String,
+    pub to: String,
+    /// Timed occurrences within the range. All-day events are only counted in
+    /// `all_day_events`.
+    pub meetings: usize,
+    pub all_day_events: usize,
+    /// Hours covered by at least one meeting, overlapping meetings count once.
+    pub hours_booked: f64,
+    /// Keyed by local date, `2024-03-18`.
+    pub hours_per_day: BTreeMap<String, f64>,
+    /// Keyed by ISO week, `2024-W12`.
+    pub hours_per_week: BTreeMap<String, f64>,
+    pub meetings_per_source: BTreeMap<String, usize>,
+    pub meetings_per_category: BTreeMap<String, usize>,
+    /// The longest span without busy blocks, see [`feed_busy_slots`].
+    pub longest_free_block: Option<FreeBlock>,
+    pub recurring: usize,
+    pub one_off: usize,
+    /// Share of meetings that are occurrences of a recurring event.
+    pub recurring_ratio: f64,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct FreeBlock {
+    pub start: String,
+    pub end: String,
+    pub minutes: i64,
+}
+
+/// Computes the [`Stats`] of the merged events of `feed` within `[from, to)`.
+/// Recurring events are expanded, so each occurrence counts as a meeting.
+/// Cancelled and transparent events are not counted, see [`busy_events`].
+pub fn feed_stats(events: &FeedEvents, feed: &FeedConfig, from: DateTime<Utc>, to: DateTime<Utc>) -> Stats {
+    let tz = feed.timezone();
+    let mut stats = Stats {
+        from: from.with_timezone(&tz).to_rfc3339(),
+        to: to.with_timezone(&tz).to_rfc3339(),
+        ..Default::default()
+    };
+    let mut booked: Vec<(DateTime<Utc>, DateTime<Utc>)> = Vec::new();
+
+    for event in &busy_events(&events.components) {
+        let recurring = event.property_value("RRULE").is_some()
+            || event.property_value("RECURRENCE-ID").is_some()
+            || event.multi_properties().contains_key("RDATE");
+        let source = event.property_value(SOURCE_PROPERTY).unwrap_or_default().to_string();
+        let categories = categories(event);
+
+        for occurrence in occurrences_between(event, tz, from, to) {
+            if occurrence.all_day {
+                stats.all_day_events += 1;
+                continue;
+            }
+
+            stats.meetings += 1;
+            if recurring {
+                stats.recurring += 1;
+            } else {
+                stats.one_off += 1;
+            }
+            *stats.meetings_per_source.entry(source.clone()).or_default() += 1;
+            for category in &categories {
+                *stats.meetings_per_category.entry(category.clone()).or_default() += 1;
+            }
+            booked.push((occurrence.start.max(from), occurrence.end.min(to)));
+        }
+    }
+
+    for (start, end) in merge_intervals(booked) {
+        for (day_start, day_end) in split_at_midnight(start, end, tz) {
+            let hours = (day_end - day_start).num_seconds() as f64 / 3600.0;
+            let day = day_start.with_timezone(&tz).date_naive();
+            let week = day.iso_week();
+
+            stats.hours_booked += hours;
+            *stats.hours_per_day.entry(day.format("%Y-%m-%d").to_string()).or_default() += hours;
+            *stats
+                .hours_per_week
+                .entry(format!("{}-W{:02}", week.year(), week.week()))
+                .or_default() += hours;
+        }
+    }
+
+    if stats.meetings > 0 {
+        stats.recurring_ratio = stats.recurring as f64 / stats.meetings as f64;
+    }
+
+    let busy = feed_busy_slots(&events.components, &events.holidays, feed, from, to);
+    stats.longest_free_block = longest_gap(&busy, from, to).map(|(start, end)| FreeBlock {
+        start: start.with_timezone(&tz).to_rfc3339(),
+        end: end.with_timezone(&tz).to_rfc3339(),
+        minutes: (end - start).num_minutes(),
+    });
+
+    stats
+}
+
+fn merge_intervals(mut intervals: Vec<(DateTime<Utc>, DateTime<Utc>)>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    intervals.sort();
+
+    let mut merged: Vec<(DateTime<Utc>, DateTime<Utc>)> = Vec::new();
+    for (start, end) in intervals.into_iter().filter(|(start, end)| start < end) {
+        match merged.last_mut() {
+            Some(last) if last.1 >= start => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Splits `[start, end)` into the parts falling on each local day.
+fn split_at_midnight(start: DateTime<Utc>, end: DateTime<Utc>, tz: Tz) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut parts = Vec::new();
+    let mut cursor = start;
+
+    while cursor < end {
+        let next_day = cursor.with_timezone(&tz).date_naive() + Duration::days(1);
+        let midnight = local_to_utc(next_day.and_time(NaiveTime::MIN), tz).unwrap_or(end);
+        let part_end = midnight.min(end).max(cursor + Duration::seconds(1));
+        parts.push((cursor, part_end));
+        cursor = part_end;
+    }
+    parts
+}
+
+/// The longest span within `[from, to)` not covered by `busy`, which has to be sorted.
+fn longest_gap(busy: &[BusySlot], from: DateTime<Utc>, to: DateTime<Utc>) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut longest: Option<(DateTime<Utc>, DateTime<Utc>)> = None;
+    let mut cursor = from;
+
+    for (start, end) in busy
+        .iter()
+        .map(|slot| (slot.start, slot.end))
+        .chain(std::iter::once((to, to)))
+    {
+        let gap = (cursor, start.min(to));
+        if gap.1 > gap.0 && longest.is_none_or(|(s, e)| gap.1 - gap.0 > e - s) {
+            longest = Some(gap);
+        }
+        cursor = cursor.max(end);
+    }
+
+    longest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone};
+    use icalendar::{Event, EventLike};
+
+    fn feed() -> FeedConfig {
+        toml::from_str("name = \"team\"\nsources = []").unwrap()
+    }
+
+    #[test]
+    fn test_counts_occurrences_and_booked_hours() {
+        // Monday 2024-03-18 to Monday 2024-03-25.
+        let from = Utc.with_ymd_and_hms(2024, 3, 18, 0, 0, 0).unwrap();
+        let to = from + Duration::days(7);
+        let at = |day: u32, hour: u32| Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap();
+
+        let standup = Event::new()
+            .starts(at(18, 9))
+            .ends(at(18, 9) + Duration::minutes(30))
+            .add_property("RRULE", "FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR")
+            .add_property(SOURCE_PROPERTY, "Work")
+            .done();
+        let mut review = Event::new();
+        review
+            .starts(at(18, 9))
+            .ends(at(18, 11))
+            .add_property(SOURCE_PROPERTY, "Work")
+            .append_multi_property(("CATEGORIES", "Review,Important"));
+        let holiday = Event::new().all_day(NaiveDate::from_ymd_opt(2024, 3, 22).unwrap()).done();
+
+        let events = FeedEvents {
+            components: vec![standup.into(), review.done().into(), holiday.into()],
+            holidays: Vec::new(),
+        };
+        let stats = feed_stats(&events, &feed(), from, to);
+
+        assert_eq!((stats.meetings, stats.recurring, stats.one_off, stats.all_day_events), (6, 5, 1, 1));
+        assert_eq!(stats.meetings_per_source.get("Work"), Some(&6));
+        assert_eq!(stats.meetings_per_category.get("Important"), Some(&1));
+        // The review covers the Monday standup: 2 hours on Monday and 4 * 0.5 on the other days.
+        assert_eq!(stats.hours_per_day.get("2024-03-18"), Some(&2.0));
+        assert_eq!(stats.hours_per_week.get("2024-W12"), Some(&4.0));
+        assert_eq!(stats.hours_booked, 4.0);
+
+        // The all-day event blocks Friday, the weekend is free.
+        let longest = stats.longest_free_block.unwrap();
+        assert_eq!(longest.start, "2024-03-23T00:00:00+00:00");
+        assert_eq!(longest.minutes, 48 * 60);
+    }
+}
@@ -1,130 +1,312 @@
-use chrono::Duration;
-use icalendar::{CalendarComponent, CalendarDateTime, Component, DatePerhapsTime, Event, EventLike};
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
+use icalendar::{Calendar, CalendarComponent, CalendarDateTime, Component, DatePerhapsTime, Event, Property};
+
+/// Resolves a date-time to an absolute point in time.
+///
+/// Floating times, dates and unknown TZIDs are interpreted in `fallback_tz`.
+pub fn resolve_utc(dt: &DatePerhapsTime, fallback_tz: Tz) -> Option<DateTime<Utc>> {
+    let (naive, tz) = match dt {
+        DatePerhapsTime::DateTime(CalendarDateTime::Utc(utc_dt)) => return Some(*utc_dt),
+        DatePerhapsTime::DateTime(CalendarDateTime::Floating(naive_dt)) => (*naive_dt, fallback_tz),
+        DatePerhapsTime::DateTime(CalendarDateTime::WithTimezone { date_time, tzid }) => {
+            (*date_time, tzid.parse::<Tz>().unwrap_or(fallback_tz))
+        }
+        DatePerhapsTime::Date(date) => (date.and_hms_opt(0, 0, 0)?, fallback_tz),
+    };
 
-fn adjust_calendar_datetime_with_offset(calendar_dt: &CalendarDateTime, offset_hours: i64) -> CalendarDateTime {
-    if offset_hours == 0 {
-        return calendar_dt.clone();
-    }
+    local_to_utc(naive, tz)
+}
 
-    let offset_duration = Duration::hours(offset_hours);
-    match calendar_dt {
-        CalendarDateTime::Floating(naive_dt) => {
-            if let Some(adjusted_dt) = naive_dt.checked_add_signed(offset_duration) {
-                CalendarDateTime::Floating(adjusted_dt)
-            } else {
-                calendar_dt.clone()
-            }
+/// Converts a wall clock time to UTC, picking the earlier time when it is
+/// ambiguous and skipping ahead over DST gaps.
+pub fn local_to_utc(naive: NaiveDateTime, tz: Tz) -> Option<DateTime<Utc>> {
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(naive + Duration::hours(1))).earliest())
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// Date-time properties moved by `shift_timezone`. EXDATE and RDATE may appear
+/// several times and hold comma separated lists.
+const SHIFTED_PROPERTIES: [&str; 3] = ["DTSTART", "DTEND", "RECURRENCE-ID"];
+const SHIFTED_MULTI_PROPERTIES: [&str; 2] = ["EXDATE", "RDATE"];
+
+/// Moves the date-times of every event by `offset` hours.
+///
+/// Only the values of DTSTART, DTEND, RECURRENCE-ID, EXDATE, RDATE and the
+/// UNTIL of RRULE change, all other properties, parameters and subcomponents are kept as they are.
+/// Dates without a time are never moved.
+pub fn shift_timezone(components: Vec<CalendarComponent>, offset: i64) -> icalendar::Calendar {
+    components
+        .into_iter()
+        .map(|component| match component {
+            CalendarComponent::Event(event) if offset != 0 => CalendarComponent::Event(shift_event(event, offset)),
+            component => component,
+        })
+        .collect::<icalendar::Calendar>()
+}
+
+fn shift_event(mut event: Event, offset: i64) -> Event {
+    let offset = Duration::hours(offset);
+
+    for key in SHIFTED_PROPERTIES {
+        if let Some(property) = event.properties().get(key) {
+            let shifted = shift_property(property, offset);
+            event.append_property(shifted);
         }
-        CalendarDateTime::Utc(utc_dt) => {
-            if let Some(adjusted_dt) = utc_dt.checked_add_signed(offset_duration) {
-                CalendarDateTime::Utc(adjusted_dt)
-            } else {
-                calendar_dt.clone()
-            }
+    }
+
+    for key in SHIFTED_MULTI_PROPERTIES {
+        let Some(properties) = event.multi_properties().get(key).cloned() else {
+            continue;
+        };
+        event.remove_multi_property(key);
+        for property in &properties {
+            event.append_multi_property(shift_property(property, offset));
         }
-        CalendarDateTime::WithTimezone { date_time, tzid } => {
-            if let Some(adjusted_dt) = date_time.checked_add_signed(offset_duration) {
-                CalendarDateTime::WithTimezone {
-                    date_time: adjusted_dt,
-                    tzid: tzid.clone(),
+    }
+
+    // UNTIL has to move with DTSTART, or the last occurrence may fall outside it.
+    if let Some(rrule) = event.properties().get("RRULE") {
+        let value = rrule
+            .value()
+            .split(';')
+            .map(|part| match part.split_once('=') {
+                Some((name, until)) if name.eq_ignore_ascii_case("UNTIL") => {
+                    format!("{name}={}", shift_value(until, offset).unwrap_or_else(|| until.to_string()))
                 }
-            } else {
-                calendar_dt.clone()
-            }
+                _ => part.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let mut shifted = Property::new("RRULE", value);
+        for parameter in rrule.params().values() {
+            shifted.append_parameter(parameter.clone());
         }
+        event.append_property(shifted);
     }
+
+    event
 }
 
-fn adjust_dateperhapstime_with_offset(dt: &DatePerhapsTime, offset_hours: i64) -> DatePerhapsTime {
-    if offset_hours == 0 {
-        return dt.clone();
+fn shift_property(property: &Property, offset: Duration) -> Property {
+    let is_date = property
+        .params()
+        .get("VALUE")
+        .is_some_and(|value| value.value().eq_ignore_ascii_case("DATE"));
+
+    let value = if is_date {
+        property.value().to_string()
+    } else {
+        property
+            .value()
+            .split(',')
+            .map(|value| {
+                // RDATE periods are `start/end` or `start/duration`.
+                value
+                    .split('/')
+                    .map(|part| shift_value(part, offset).unwrap_or_else(|| part.to_string()))
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    let mut shifted = Property::new(property.key(), value);
+    for parameter in property.params().values() {
+        shifted.append_parameter(parameter.clone());
     }
+    shifted
+}
 
-    match dt {
-        DatePerhapsTime::DateTime(calendar_dt) => {
-            let adjusted_calendar_dt = adjust_calendar_datetime_with_offset(calendar_dt, offset_hours);
-            DatePerhapsTime::DateTime(adjusted_calendar_dt)
-        }
-        DatePerhapsTime::Date(date) => {
-            DatePerhapsTime::Date(*date)
+/// Shifts a single `DATE-TIME` value, keeping its UTC marker. Anything else yields `None`.
+fn shift_value(value: &str, offset: Duration) -> Option<String> {
+    let (naive, suffix) = match value.trim().strip_suffix('Z') {
+        Some(utc) => (utc, "Z"),
+        None => (value.trim(), ""),
+    };
+    let shifted = NaiveDateTime::parse_from_str(naive, "%Y%m%dT%H%M%S").ok()?.checked_add_signed(offset)?;
+
+    Some(format!("{}{suffix}", shifted.format("%Y%m%dT%H%M%S")))
+}
+
+/// How many years before and after the current one a generated VTIMEZONE covers at most.
+const GENERATED_YEARS: i32 = 5;
+
+/// Deduplicates VTIMEZONE components by TZID and drops the ones no component refers to.
+///
+/// When the sources disagree about the definition of a TZID, or a referenced IANA zone
+/// comes without any definition, a canonical one is generated from chrono-tz.
+pub fn normalize_timezones(calendar: Calendar) -> Calendar {
+    let Calendar { properties, components } = calendar;
+    let (timezones, rest): (Vec<_>, Vec<_>) = components.into_iter().partition(|component| {
+        matches!(component, CalendarComponent::Other(other) if other.component_kind() == "VTIMEZONE")
+    });
+
+    let mut references = BTreeMap::new();
+    for component in &rest {
+        match component {
+            CalendarComponent::Event(event) => collect_tzids(event, &mut references),
+            CalendarComponent::Todo(todo) => collect_tzids(todo, &mut references),
+            CalendarComponent::Other(other) => collect_tzids(other, &mut references),
+            _ => {}
         }
     }
-}
 
-pub fn shift_timezone(components: Vec<CalendarComponent>, offset: i64) -> icalendar::Calendar {
-    components
-        .into_iter()
-        .map(|component| {
-            if let Some(event) = component.as_event() {
-                let mut new_event = Event::new();
+    let mut definitions: HashMap<String, Vec<CalendarComponent>> = HashMap::new();
+    for timezone in timezones {
+        if let CalendarComponent::Other(other) = &timezone {
+            if let Some(tzid) = other.property_value("TZID") {
+                definitions.entry(tzid.to_string()).or_default().push(timezone);
+            }
+        }
+    }
 
-                if let Some(uid) = event.get_uid() {
-                    new_event.uid(uid);
+    let this_year = Utc::now().year();
+    let mut normalized = Vec::new();
+
+    for (tzid, (first_year, last_year)) in references {
+        let candidates = definitions.remove(&tzid).unwrap_or_default();
+        let agreeing = candidates.windows(2).all(|pair| match (&pair[0], &pair[1]) {
+            (CalendarComponent::Other(a), CalendarComponent::Other(b)) => a.components() == b.components(),
+            _ => false,
+        });
+
+        let definition = match (candidates.first(), tzid.parse::<Tz>()) {
+            (Some(first), _) if agreeing => Some(first.clone()),
+            // Recurring events reach past their first occurrence, so cover a little future.
+            // Years far from now are left to the first and last observance, or a stray
+            // year 0001 would have us scan two thousand years of transitions.
+            (_, Ok(tz)) => vtimezone_from_tz(
+                tz,
+                first_year.clamp(this_year - GENERATED_YEARS, this_year),
+                last_year.clamp(this_year, this_year + GENERATED_YEARS) + 1,
+            ),
+            (first, Err(_)) => {
+                if first.is_some() {
+                    tracing::warn!(%tzid, "conflicting definitions for unknown timezone, keeping the first one");
                 }
+                first.cloned()
+            }
+        };
 
-                if let Some(summary) = event.get_summary() {
-                    new_event.summary(summary);
-                }
+        normalized.extend(definition);
+    }
+    normalized.extend(rest);
 
-                if let Some(description) = event.get_description() {
-                    new_event.description(description);
-                }
+    Calendar {
+        properties,
+        components: normalized,
+    }
+}
 
-                if let Some(location) = event.get_location() {
-                    new_event.location(location);
-                }
+/// Records every TZID parameter of `component` and its children, together with the
+/// range of years the referencing values fall into.
+fn collect_tzids<C: Component>(component: &C, references: &mut BTreeMap<String, (i32, i32)>) {
+    let properties = component
+        .properties()
+        .values()
+        .chain(component.multi_properties().values().flatten());
+
+    for property in properties {
+        let Some(tzid) = property.params().get("TZID") else {
+            continue;
+        };
+
+        let year = property.value().get(..4).and_then(|year| year.parse::<i32>().ok());
+        let range = references.entry(tzid.value().to_string()).or_insert((i32::MAX, i32::MIN));
+        if let Some(year) = year {
+            *range = (range.0.min(year), range.1.max(year));
+        }
+    }
 
-                if let Some(status) = event.get_status() {
-                    new_event.status(status);
-                }
+    for child in component.components() {
+        collect_tzids(child, references);
+    }
+}
 
-                if let Some(start) = event.get_start() {
-                    let adjusted_start = adjust_dateperhapstime_with_offset(&start, offset);
-                    new_event.starts(adjusted_start);
+/// Builds a VTIMEZONE listing every offset transition of `tz` between the given years.
+fn vtimezone_from_tz(tz: Tz, first_year: i32, last_year: i32) -> Option<CalendarComponent> {
+    let start = Utc.with_ymd_and_hms(first_year, 1, 1, 0, 0, 0).single()?;
+    let end = Utc.with_ymd_and_hms(last_year + 1, 1, 1, 0, 0, 0).single()?;
+
+    let offset_at = |instant: DateTime<Utc>| tz.offset_from_utc_datetime(&instant.naive_utc());
+    let same_offset = |a: &<Tz as TimeZone>::Offset, b: &<Tz as TimeZone>::Offset| {
+        a.fix() == b.fix() && a.abbreviation() == b.abbreviation()
+    };
+
+    let mut lines = vec!["BEGIN:VTIMEZONE".to_string(), format!("TZID:{}", tz.name())];
+
+    let mut previous = offset_at(start);
+    push_observance(&mut lines, start, &previous, &previous);
+
+    let mut day = start;
+    while day < end {
+        let next_day = day + Duration::days(1);
+        if !same_offset(&offset_at(next_day), &previous) {
+            // Narrow the change down to the second it happens.
+            let (mut before, mut after) = (day, next_day);
+            while after - before > Duration::seconds(1) {
+                let middle = before + (after - before) / 2;
+                if same_offset(&offset_at(middle), &previous) {
+                    before = middle;
+                } else {
+                    after = middle;
                 }
+            }
 
-                if let Some(end) = event.get_end() {
-                    let adjusted_end = adjust_dateperhapstime_with_offset(&end, offset);
-                    new_event.ends(adjusted_end);
-                }
+            let current = offset_at(after);
+            push_observance(&mut lines, after, &previous, &current);
+            previous = current;
+        }
+        day = next_day;
+    }
 
-                if let Some(created) = event.get_timestamp() {
-                    if offset != 0 {
-                        let offset_duration = Duration::hours(offset);
-                        if let Some(adjusted_timestamp) = created.checked_add_signed(offset_duration) {
-                            new_event.timestamp(adjusted_timestamp);
-                        } else {
-                            new_event.timestamp(created);
-                        }
-                    } else {
-                        new_event.timestamp(created);
-                    }
-                }
+    lines.push("END:VTIMEZONE".to_string());
+    lines.join("\r\n").parse::<CalendarComponent>().ok()
+}
 
-                for (key, value) in event.properties() {
-                    match key.as_str() {
-                        "UID" | "SUMMARY" | "DESCRIPTION" | "LOCATION" | "STATUS" |
-                        "DTSTART" | "DTEND" | "DTSTAMP" => {
-                        }
-                        _ => {
-                            new_event.add_property(key, value.value());
-                        }
-                    }
-                }
+fn push_observance(
+    lines: &mut Vec<String>,
+    instant: DateTime<Utc>,
+    from: &<Tz as TimeZone>::Offset,
+    to: &<Tz as TimeZone>::Offset,
+) {
+    let kind = if to.dst_offset().is_zero() { "STANDARD" } else { "DAYLIGHT" };
+    let local_start = instant.naive_utc() + Duration::seconds(from.fix().local_minus_utc().into());
+
+    lines.push(format!("BEGIN:{kind}"));
+    lines.push(format!("DTSTART:{}", local_start.format("%Y%m%dT%H%M%S")));
+    lines.push(format!("TZOFFSETFROM:{}", format_utc_offset(from.fix().local_minus_utc())));
+    lines.push(format!("TZOFFSETTO:{}", format_utc_offset(to.fix().local_minus_utc())));
+    if let Some(name) = to.abbreviation() {
+        lines.push(format!("TZNAME:{name}"));
+    }
+    lines.push(format!("END:{kind}"));
+}
 
-                CalendarComponent::Event(new_event.done())
-            } else {
-                component
-            }
-        })
-        .collect::<icalendar::Calendar>()
+fn format_utc_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if seconds == 0 {
+        format!("{sign}{hours:02}{minutes:02}")
+    } else {
+        format!("{sign}{hours:02}{minutes:02}{seconds:02}")
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use chrono::NaiveDate;
-    use icalendar::{Calendar, CalendarDateTime, DatePerhapsTime};
+    use icalendar::{Calendar, CalendarDateTime, DatePerhapsTime, EventLike};
 
     #[test]
     fn test_timezone_shift_positive_offset() {
@@ -268,4 +450,126 @@ mod tests {
             panic!("Expected event component");
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_timezone_shift_keeps_parameters_and_repeated_properties() {
+        let text = "BEGIN:VEVENT\r\nUID:series\r\nDTSTART;TZID=Europe/Berlin:20240101T090000\r\n\
+                    DTEND;TZID=Europe/Berlin:20240101T100000\r\nRRULE:FREQ=DAILY\r\n\
+                    EXDATE;TZID=Europe/Berlin:20240102T090000,20240103T090000\r\n\
+                    EXDATE;TZID=Europe/Berlin:20240105T090000\r\nRDATE;VALUE=DATE:20240110\r\n\
+                    ATTENDEE;ROLE=CHAIR;CN=Alice:mailto:alice@example.com\r\n\
+                    ATTENDEE;CN=Bob:mailto:bob@example.com\r\nBEGIN:VALARM\r\nACTION:DISPLAY\r\n\
+                    TRIGGER:-PT10M\r\nEND:VALARM\r\nEND:VEVENT\r\n";
+        let component = text.parse::<CalendarComponent>().unwrap();
+
+        let shifted = shift_timezone(vec![component], 2).to_string();
+
+        assert!(shifted.contains("DTSTART;TZID=Europe/Berlin:20240101T110000"));
+        assert!(shifted.contains("DTEND;TZID=Europe/Berlin:20240101T120000"));
+        assert!(shifted.contains("EXDATE;TZID=Europe/Berlin:20240102T110000,20240103T110000"));
+        assert!(shifted.contains("EXDATE;TZID=Europe/Berlin:20240105T110000"));
+        assert!(shifted.contains("RDATE;VALUE=DATE:20240110"));
+        assert!(shifted.contains("ROLE=CHAIR"));
+        assert!(shifted.contains("CN=Bob:mailto:bob@example.com"));
+        assert!(shifted.contains("BEGIN:VALARM"));
+    }
+
+    fn vtimezone(tzid: &str, offset_to: &str) -> String {
+        format!(
+            "BEGIN:VTIMEZONE\r\nTZID:{tzid}\r\nBEGIN:STANDARD\r\nDTSTART:19700101T000000\r\n\
+             TZOFFSETFROM:{offset_to}\r\nTZOFFSETTO:{offset_to}\r\nEND:STANDARD\r\nEND:VTIMEZONE\r\n"
+        )
+    }
+
+    #[test]
+    fn test_timezone_shift_moves_rrule_until() {
+        let text = "BEGIN:VEVENT\r\nUID:series\r\nDTSTART:20240101T230000Z\r\n\
+                    RRULE:FREQ=DAILY;UNTIL=20240105T230000Z;BYHOUR=23\r\nEND:VEVENT\r\n\
+                    BEGIN:VEVENT\r\nUID:days\r\nDTSTART;VALUE=DATE:20240101\r\n\
+                    RRULE:FREQ=WEEKLY;UNTIL=20240301\r\nEND:VEVENT\r\n";
+        let components = format!("BEGIN:VCALENDAR\r\n{text}END:VCALENDAR\r\n")
+            .parse::<Calendar>()
+            .unwrap()
+            .components;
+
+        let shifted = shift_timezone(components, 2).to_string();
+
+        assert!(shifted.contains("DTSTART:20240102T010000Z"));
+        assert!(shifted.contains("RRULE:FREQ=DAILY;UNTIL=20240106T010000Z;BYHOUR=23"));
+        assert!(shifted.contains("RRULE:FREQ=WEEKLY;UNTIL=20240301"));
+    }
+
+    #[test]
+    fn test_normalize_timezones_deduplicates_and_drops_unused() {
+        let text = format!(
+            "BEGIN:VCALENDAR\r\n{}{}{}\
+             BEGIN:VEVENT\r\nUID:a\r\nDTSTART;TZID=Custom/Zone:20240101T100000\r\nEND:VEVENT\r\n\
+             END:VCALENDAR\r\n",
+            vtimezone("Custom/Zone", "+0100"),
+            vtimezone("Custom/Zone", "+0100"),
+            vtimezone("Unused/Zone", "+0200"),
+        );
+        let calendar = text.parse::<Calendar>().unwrap();
+
+        let normalized = normalize_timezones(calendar);
+
+        let tzids = normalized
+            .components
+            .iter()
+            .filter_map(|c| match c {
+                CalendarComponent::Other(other) => other.property_value("TZID"),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tzids, vec!["Custom/Zone"]);
+        assert_eq!(normalized.components.len(), 2);
+    }
+
+    #[test]
+    fn test_normalize_timezones_regenerates_conflicting_definitions() {
+        let text = format!(
+            "BEGIN:VCALENDAR\r\n{}{}\
+             BEGIN:VEVENT\r\nUID:a\r\nDTSTART;TZID=Europe/Berlin:20240101T100000\r\nEND:VEVENT\r\n\
+             END:VCALENDAR\r\n",
+            vtimezone("Europe/Berlin", "+0100"),
+            vtimezone("Europe/Berlin", "+0200"),
+        );
+        let calendar = text.parse::<Calendar>().unwrap();
+
+        let normalized = normalize_timezones(calendar);
+
+        let CalendarComponent::Other(timezone) = &normalized.components[0] else {
+            panic!("Expected timezone component");
+        };
+        let daylight = timezone
+            .components()
+            .iter()
+            .filter(|observance| observance.component_kind() == "DAYLIGHT")
+            .collect::<Vec<_>>();
+        assert!(!daylight.is_empty());
+        assert_eq!(daylight[0].property_value("TZOFFSETFROM"), Some("+0100"));
+        assert_eq!(daylight[0].property_value("TZOFFSETTO"), Some("+0200"));
+        assert_eq!(daylight[0].property_value("TZNAME"), Some("CEST"));
+    }
+
+    #[test]
+    fn test_generated_timezones_stay_near_the_present() {
+        let text = "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:a\r\n\
+                    DTSTART;TZID=Europe/Berlin:00010101T100000\r\n\
+                    DTEND;TZID=Europe/Berlin:99991231T100000\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+        let calendar = text.parse::<Calendar>().unwrap();
+
+        let normalized = normalize_timezones(calendar);
+
+        let CalendarComponent::Other(timezone) = &normalized.components[0] else {
+            panic!("Expected timezone component");
+        };
+        let this_year = Utc::now().year();
+        let years = timezone
+            .components()
+            .iter()
+            .filter_map(|observance| observance.property_value("DTSTART")?.get(..4)?.parse::<i32>().ok())
+            .collect::<Vec<_>>();
+        assert!(years.iter().all(|year| (this_year - 5..=this_year + 6).contains(year)), "{years:?}");
+    }
+}
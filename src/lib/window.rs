@@ -0,0 +1,245 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+use icalendar::{Calendar, CalendarComponent, CalendarDateTime, Component, DatePerhapsTime, Event};
+
+use crate::lib::recurrence::occurrences;
+use crate::lib::timezone::local_to_utc;
+
+/// The span of time a feed publishes events for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: DateTime<Utc>,
+    /// `None` keeps everything from `start` on.
+    pub end: Option<DateTime<Utc>>,
+}
+
+impl TimeWindow {
+    /// From the start of the day `past_days` ago up to the end of the day
+    /// `future_days` from now, both in `tz`. Without a past limit only events
+    /// that are ongoing or upcoming are kept. `None` if neither limit is set.
+    pub fn around(now: DateTime<Utc>, tz: Tz, past_days: Option<u32>, future_days: Option<u32>) -> Option<Self> {
+        if past_days.is_none() && future_days.is_none() {
+            return None;
+        }
+
+        let midnight = |days: i64| day_start(now.with_timezone(&tz).date_naive() + Duration::days(days), tz);
+
+        Some(TimeWindow {
+            start: midnight(-i64::from(past_days.unwrap_or(0)))?,
+            end: match future_days {
+                Some(days) => Some(midnight(i64::from(days) + 1)?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Midnight of the day `now` falls on in `tz`.
+pub fn start_of_day(now: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+    day_start(now.with_timezone(&tz).date_naive(), tz).unwrap_or(now)
+}
+
+fn day_start(date: NaiveDate, tz: Tz) -> Option<DateTime<Utc>> {
+    local_to_utc(date.and_time(NaiveTime::MIN), tz)
+}
+
+/// Drops events without any occurrence in `window` and cuts recurring series
+/// off at its end. Floating times and dates are interpreted in `tz`.
+pub fn apply_time_window(calendar: Calendar, window: &TimeWindow, tz: Tz) -> Calendar {
+    calendar
+        .components
+        .into_iter()
+        .filter_map(|component| {
+            let CalendarComponent::Event(event) = &component else {
+                return Some(component);
+            };
+            if event.get_start().is_none() {
+                return Some(component);
+            }
+
+            let overlaps = occurrences(event, tz)
+                .take_while(|occurrence| window.end.is_none_or(|end| occurrence.start < end))
+                .any(|occurrence| occurrence.overlaps(window.start, window.end.unwrap_or(DateTime::<Utc>::MAX_UTC)));
+
+            match (overlaps, window.end, event.property_value("RRULE")) {
+                (false, _, _) => None,
+                (true, Some(end), Some(rrule)) => Some(CalendarComponent::Event(truncate_series(event, rrule, end, tz))),
+                (true, _, _) => Some(component),
+            }
+        })
+        .collect::<Calendar>()
+}
+
+/// Ends the RRULE of a recurring event at `end`, leaving everything else as is.
+///
+/// Series that already end before `end`, by COUNT or UNTIL, are not touched.
+/// UNTIL takes the value type of DTSTART as RFC 5545 requires: a date for
+/// all-day events, a floating time for floating events and UTC otherwise.
+fn truncate_series(event: &Event, rrule: &str, end: DateTime<Utc>, tz: Tz) -> Event {
+    if !occurrences(event, tz).any(|occurrence| occurrence.start >= end) {
+        return event.clone();
+    }
+
+    // The window end is exclusive, UNTIL is inclusive.
+    let last = end - Duration::seconds(1);
+    let until = match event.get_start() {
+        Some(DatePerhapsTime::Date(_)) => last.with_timezone(&tz).format("%Y%m%d").to_string(),
+        Some(DatePerhapsTime::DateTime(CalendarDateTime::Floating(_))) => {
+            last.with_timezone(&tz).format("%Y%m%dT%H%M%S").to_string()
+        }
+        _ => last.format("%Y%m%dT%H%M%SZ").to_string(),
+    };
+
+    let truncated_rrule = rrule
+        .split(';')
+        .filter(|part| {
+            let key = part.split('=').next().unwrap_or_default().trim().to_ascii_uppercase();
+            key != "UNTIL" && key != "COUNT"
+        })
+        .chain(std::iter::once(format!("UNTIL={until}").as_str()))
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let mut event = event.clone();
+    event.add_property("RRULE", truncated_rrule);
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use icalendar::EventLike;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 15, 12, 0, 0).unwrap()
+    }
+
+    fn summaries(calendar: &Calendar) -> Vec<&str> {
+        calendar
+            .components
+            .iter()
+            .filter_map(|c| c.as_event()?.get_summary())
+            .collect()
+    }
+
+    #[test]
+    fn test_keeps_ongoing_and_upcoming_events() {
+        let day = |d| NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+        let calendar = || -> Calendar {
+            [
+                Event::new().summary("Last week").all_day(day(8)).done(),
+                Event::new().summary("Conference").starts(day(14)).ends(day(17)).done(),
+                Event::new().summary("Next week").all_day(day(22)).done(),
+                Event::new().summary("Next month").all_day(NaiveDate::from_ymd_opt(2024, 4, 20).unwrap()).done(),
+            ]
+            .into_iter()
+            .collect()
+        };
+
+        let window = TimeWindow::around(now(), Tz::UTC, None, Some(14)).unwrap();
+        let filtered = apply_time_window(calendar(), &window, Tz::UTC);
+        assert_eq!(summaries(&filtered), vec!["Conference", "Next week"]);
+
+        let window = TimeWindow::around(now(), Tz::UTC, Some(7), Some(14)).unwrap();
+        let filtered = apply_time_window(calendar(), &window, Tz::UTC);
+        assert_eq!(summaries(&filtered), vec!["Last week", "Conference", "Next week"]);
+    }
+
+    #[test]
+    fn test_keeps_series_started_long_ago() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 6, 9, 0, 0).unwrap();
+        let calendar: Calendar = [
+            Event::new()
+                .summary("Standup")
+                .starts(start)
+                .ends(start + Duration::minutes(15))
+                .add_property("RRULE", "FREQ=WEEKLY;BYDAY=MO")
+                .done(),
+            Event::new()
+                .summary("Finished")
+                .starts(start)
+                .ends(start + Duration::minutes(15))
+                .add_property("RRULE", "FREQ=WEEKLY;COUNT=10")
+                .done(),
+        ]
+        .into_iter()
+        .collect();
+
+        let window = TimeWindow::around(now(), Tz::UTC, None, Some(30)).unwrap();
+        let filtered = apply_time_window(calendar, &window, Tz::UTC);
+
+        assert_eq!(summaries(&filtered), vec!["Standup"]);
+    }
+
+    fn parse_event(text: &str) -> Event {
+        match text.replace('\n', "\r\n").parse::<CalendarComponent>().unwrap() {
+            CalendarComponent::Event(event) => event,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_truncation_only_touches_rrule() {
+        let event = parse_event(
+            "BEGIN:VEVENT
+UID:series
+DTSTART;TZID=Europe/Berlin:20240101T090000
+DTEND;TZID=Europe/Berlin:20240101T100000
+RRULE:FREQ=DAILY;COUNT=1000
+EXDATE;TZID=Europe/Berlin:20240102T090000
+EXDATE;TZID=Europe/Berlin:20240103T090000
+ATTENDEE;CN=Alice;PARTSTAT=ACCEPTED:mailto:alice@example.com
+ATTENDEE;CN=Bob:mailto:bob@example.com
+X-CUSTOM:kept
+BEGIN:VALARM
+ACTION:DISPLAY
+TRIGGER:-PT10M
+END:VALARM
+END:VEVENT
+",
+        );
+
+        let truncated = truncate_series(&event, "FREQ=DAILY;COUNT=1000", now(), Tz::UTC).to_string();
+
+        assert!(truncated.contains("RRULE:FREQ=DAILY;UNTIL=20240315T115959Z"));
+        assert!(!truncated.contains("COUNT"));
+        assert!(truncated.contains("DTSTART;TZID=Europe/Berlin:20240101T090000"));
+        assert_eq!(truncated.matches("EXDATE;TZID=Europe/Berlin").count(), 2);
+        assert!(truncated.contains("PARTSTAT=ACCEPTED"));
+        assert!(truncated.contains("CN=Bob:mailto:bob@example.com"));
+        assert!(truncated.contains("X-CUSTOM:kept"));
+        assert!(truncated.contains("BEGIN:VALARM"));
+    }
+
+    #[test]
+    fn test_truncation_until_follows_dtstart_type() {
+        let all_day = parse_event(
+            "BEGIN:VEVENT
+UID:birthday
+DTSTART;VALUE=DATE:20200315
+RRULE:FREQ=YEARLY
+END:VEVENT
+",
+        );
+        let ended = parse_event(
+            "BEGIN:VEVENT
+UID:ended
+DTSTART:20240101T090000
+RRULE:FREQ=DAILY;UNTIL=20240105T090000
+END:VEVENT
+",
+        );
+
+        let end = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(
+            truncate_series(&all_day, "FREQ=YEARLY", end, Tz::UTC).property_value("RRULE"),
+            Some("FREQ=YEARLY;UNTIL=20241231")
+        );
+        assert_eq!(
+            truncate_series(&ended, "FREQ=DAILY;UNTIL=20240105T090000", end, Tz::UTC).property_value("RRULE"),
+            Some("FREQ=DAILY;UNTIL=20240105T090000")
+        );
+    }
+}